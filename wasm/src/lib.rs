@@ -1,3 +1,6 @@
+use std::borrow::Cow;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
 use wasm_bindgen::prelude::*;
 
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
@@ -6,13 +9,499 @@ use wasm_bindgen::prelude::*;
 #[global_allocator]
 static ALLOC: wee_alloc::WeeAlloc = wee_alloc::WeeAlloc::INIT;
 
+/// `last_status` bit: history was flushed because a scene cut was detected.
+const STATUS_AUTO_RESET: u32 = 1 << 0;
+/// `last_status` bit: the input was not `width * height` long.
+const STATUS_SIZE_MISMATCH: u32 = 1 << 1;
+/// `last_status` bit: the frame was rejected as an outlier and given no weight.
+const STATUS_OUTLIER: u32 = 1 << 2;
+
+/// Trimap pixel codes: definite background, unknown band, definite foreground.
+const TRIMAP_BACKGROUND: u8 = 0;
+const TRIMAP_UNKNOWN: u8 = 128;
+const TRIMAP_FOREGROUND: u8 = 255;
+
+/// Raw input confidence a click must land on for `set_robust_seed` to seed
+/// from the current frame.
+const ROBUST_SEED_CONFIDENCE: f32 = 0.9;
+
+/// How far the flood fill looks for a foreground pixel around a click that
+/// landed on background.
+const SEED_SEARCH_RADIUS: i32 = 20;
+
+/// Drop in input confidence per pixel, along the growth direction, that stops
+/// `set_gradient_dilate` from growing further that way.
+const GRADIENT_DILATE_DROP: f32 = 0.25;
+
+/// Intervals of the gamma tables used by the gamma-space edge blur; fine
+/// enough that interpolation stays within about 0.005 of `powf`.
+const FEATHER_LUT_SIZE: usize = 4096;
+
+/// Share of the frame, and mean input confidence over it, beyond and below
+/// which `likely_background` flags a selection.
+const BACKGROUND_AREA_FRACTION: f32 = 0.4;
+const BACKGROUND_CONFIDENCE: f32 = 0.7;
+
+/// Mean absolute difference per pixel above which a block match of
+/// `set_block_match_align` is rejected and the block left unwarped.
+const BLOCK_MATCH_MAX_ERROR: f32 = 0.1;
+
+/// Final values up to this (or up to the floor, if higher) count as background
+/// for `set_background_floor`.
+const BACKGROUND_FLOOR_CUTOFF: f32 = 0.02;
+
+/// Number of recent flicker values `is_stable` requires.
+const STABILITY_WINDOW: usize = 5;
+
+/// Log-polar grid of `set_logpolar_smoothing`: angle bins, log-radius bins,
+/// and the largest log-radius shift (scale change) searched either way.
+const LOGPOLAR_ANGLES: usize = 128;
+const LOGPOLAR_RADII: usize = 64;
+const LOGPOLAR_SCALE_STEPS: i32 = 4;
+
+/// Average centroid movement in pixels per frame below which
+/// `motion_direction` reports no direction.
+const MIN_MOTION_PX: f32 = 0.5;
+
+/// Erosion radius `suggest_element` tries each candidate shape at.
+const SUGGEST_ELEMENT_RADIUS: i32 = 2;
+
+/// Structuring elements kept in the cache; the oldest radius is dropped first.
+/// Erosion, dilation and their downscaled radii fit comfortably.
+const KERNEL_CACHE_SIZE: usize = 8;
+
+/// Shared `(dx, dy)` offsets of one structuring element.
+type Kernel = Rc<[(i32, i32)]>;
+
+/// How morphology and flood fill treat pixels beyond the frame edges.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum BorderMode {
+    /// Out-of-bounds pixels count as background (the default).
+    Constant,
+    /// The left and right edges are adjacent, as in equirectangular 360° video.
+    /// Top and bottom still behave like `Constant`.
+    Wrap,
+}
+
+/// Shape of the structuring element used by erosion and dilation.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum ElementShape {
+    Circle,
+    Square,
+    HorizontalLine,
+    VerticalLine,
+}
+
+/// Memory order of the masks returned by the stateful refine calls.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum OutputLayout {
+    RowMajor,
+    /// Transposed: pixel `(x, y)` is at `x * height + y`.
+    ColumnMajor,
+}
+
+/// How the frames in the history window are combined.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum TemporalMode {
+    /// Weighted mean (the default).
+    Mean,
+    /// Per-pixel maximum, for accumulation effects.
+    Max,
+}
+
+/// Which binary image the flood fill computes connectivity on.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum FloodSource {
+    /// The eroded threshold (the default), where bridges are broken.
+    Eroded,
+    /// The threshold itself, before erosion.
+    Binary,
+    /// The average above half the threshold.
+    Soft,
+}
+
+/// What the refine calls return for an input of the wrong length.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum MismatchMode {
+    /// The input itself, unchanged (the default).
+    ReturnInput,
+    /// All zeros of the expected `width * height` length.
+    ReturnEmpty,
+    /// `ReturnEmpty`, and `last_status` reports `STATUS_SIZE_MISMATCH`.
+    Flag,
+}
+
+/// Intermediate buffers from one pass of the pipeline.
+struct Stages {
+    binary: Vec<u8>,
+    eroded: Vec<u8>,
+    final_mask: Vec<f32>,
+    // Distinct components reached by the seeds
+    components: usize,
+    // Pixel the fill for the last seed started from
+    seed: Option<(usize, usize)>,
+    // The isolated binary before dilation (empty unless `keep_isolated`)
+    isolated: Vec<u8>,
+    // Auto-gain factor applied to the average, and the lowest relaxed threshold factor used
+    gain: f32,
+    relax: f32,
+    // Dilate radius actually applied after the growth cap
+    dilate: i32,
+}
+
+/// Per-call variations of one stateful refine, passed down to it rather than
+/// written into the settings.
+#[derive(Clone, Copy, Default)]
+struct FrameOptions<'a> {
+    // Newest history frames to average, if fewer than all (`refine_mask_dt_aware`)
+    window: Option<usize>,
+    // Geodesic reach of the isolating fill from the seed (`refine_mask_geodesic`)
+    geodesic_limit: Option<f32>,
+    // Process the frame as binary input (`refine_mask_bits_in`)
+    binary_input: bool,
+    // RGBA source and cell size to snap the result to (`refine_snap_superpixels`)
+    snap: Option<(&'a [u8], usize)>,
+}
+
+/// Where a chunked refine (`refine_mask_begin`) has got to.
+#[derive(Clone, Copy, PartialEq)]
+enum ChunkStage {
+    Average,
+    Erode,
+    Fill,
+    Dilate,
+    Done,
+}
+
+/// State of a chunked refine between `refine_mask_step` calls.
+struct ChunkedRefine {
+    input: Vec<f32>,
+    seed: Option<(usize, usize)>,
+    stage: ChunkStage,
+    // First row the next step of the current stage processes
+    row: usize,
+    binary: Vec<u8>,
+    eroded: Vec<u8>,
+    isolated: Vec<u8>,
+    dilated: Vec<u8>,
+    components: usize,
+    // Pixel the fill started from
+    start: Option<(usize, usize)>,
+}
+
+/// One stored history frame, at the precision chosen by
+/// `set_history_precision`. Quantized frames hold `round(v * max)` of the
+/// input clamped to 0-1.
+enum Frame {
+    Full(Vec<f32>),
+    Byte(Vec<u8>),
+    Half(Vec<u16>),
+}
+
+impl Frame {
+    fn encode(values: &[f32], bits: u8) -> Frame {
+        match bits {
+            8 => Frame::Byte(values.iter().map(|&v| (v.clamp(0.0, 1.0) * 255.0).round() as u8).collect()),
+            16 => Frame::Half(values.iter().map(|&v| (v.clamp(0.0, 1.0) * 65535.0).round() as u16).collect()),
+            _ => Frame::Full(values.to_vec()),
+        }
+    }
+
+    // Full-precision frames are borrowed rather than copied
+    fn decode(&self) -> Cow<'_, [f32]> {
+        match self {
+            Frame::Full(f) => Cow::Borrowed(f),
+            Frame::Byte(b) => b.iter().map(|&v| v as f32 / 255.0).collect(),
+            Frame::Half(h) => h.iter().map(|&v| v as f32 / 65535.0).collect(),
+        }
+    }
+}
+
+/// Temporal state of one selection layer while another layer is active.
+#[derive(Default)]
+struct Layer {
+    name: String,
+    history: Vec<Frame>,
+    history_weights: Vec<f32>,
+    fade: Vec<f32>,
+    last_final: Vec<f32>,
+}
+
+/// Tunables set through the setters; `reset_config` restores these defaults.
+#[derive(Clone, PartialEq)]
+struct Config {
+    border_mode: BorderMode,
+    // 3x3 median pre-filter applied to each incoming frame
+    spatial_median: bool,
+    // Exponent applied to the final alpha values
+    alpha_gamma: f32,
+    // Confidence-to-alpha lookup table, sampled with linear interpolation (empty = identity)
+    alpha_curve: Vec<f32>,
+    // Frames a vanished pixel takes to fade to zero (0 = off)
+    fadeout_frames: usize,
+    // Structuring-element radii for bridge-breaking erosion and edge-restoring dilation
+    erode_radius: i32,
+    dilate_radius: i32,
+    // Rescale each averaged frame so its 99th percentile maps to 1.0
+    auto_gain: bool,
+    // Foreground cut-off applied to the averaged mask
+    threshold: f32,
+    // Grow the isolated component back before re-applying soft values
+    dilate_after_isolate: bool,
+    // Majority-vote the binary boundary to round off stair-steps
+    edge_smooth: bool,
+    // Weight of the previous smoothed box in `last_bounding_box` (0 = raw)
+    bbox_smoothing: f32,
+    // Treat incoming masks as hard 0/1 and return a hard 0/1 result
+    binary_input: bool,
+    // Weight of the new result against the previous output
+    result_blend: f32,
+    // Pixel height relative to its width, for the circular structuring element
+    pixel_aspect: f32,
+    // Structuring element shape and the full length of line elements (0 = 2r+1)
+    element_shape: ElementShape,
+    element_length: usize,
+    // Rotation of the structuring element in radians, clockwise on screen
+    element_rotation: f32,
+    // Input confidence that forces a pixel in (within the component's box); > 1 = off
+    confidence_floor: f32,
+    // Flush history when the mean frame difference exceeds the sensitivity
+    auto_reset: bool,
+    auto_reset_sensitivity: f32,
+    // Keep the thinned skeleton of the thresholded mask through erosion
+    preserve_thin: bool,
+    // Ignore clicks and follow the previous selection instead
+    selection_locked: bool,
+    // Real-time length of the history window for `refine_mask_dt_aware` (0 = off)
+    smoothing_ms: f32,
+    // Run the bridge-breaking erosion and its matching dilation
+    erosion_enabled: bool,
+    // Fraction of the averaged value removed where the latest frame is empty
+    deghost: f32,
+    output_layout: OutputLayout,
+    // Disk around each click that erosion leaves alone (0 = off)
+    protect_click_radius: usize,
+    // Emit 1.0 across the isolated region instead of the soft input values
+    hard_output: bool,
+    // Components labeling stops after (0 = unlimited)
+    max_components: usize,
+    // Dilate the re-applied soft values (max filter) instead of the binary
+    grayscale_dilate: bool,
+    // Blur edges on the mask values as-is (true) or gamma-encoded
+    feather_linear: bool,
+    // Integer factor the morphology runs downscaled by, and max-pool (vs. area-average) downscaling
+    process_scale: usize,
+    downscale_max: bool,
+    // Keep each frame's temporal average for `last_averaged`
+    keep_averaged: bool,
+    // Holes in the selection below this many pixels are filled (0 = off)
+    max_hole_area: u32,
+    // Seed from the raw frame's confident pixels when the smoothed map has none at the click
+    robust_seed: bool,
+    on_mismatch: MismatchMode,
+    // Feather the final mask where erosion removed pixels the opening couldn't restore
+    erosion_guided_feather: bool,
+    // Average over time only near the previous selection; elsewhere use the latest frame
+    localized_smoothing: bool,
+    // Retry a click that finds nothing at successively lower thresholds
+    click_threshold_relax: bool,
+    // Keep each frame's isolated binary before dilation for `last_isolated`
+    keep_isolated: bool,
+    temporal_mode: TemporalMode,
+    // Replace NaN/inf and clamp to 0-1 before anything else sees the input
+    sanitize_input: bool,
+    // Path length the flood fill may travel from its seed; only set on the context of `refine_mask_geodesic`
+    geodesic_limit: Option<f32>,
+    // Grow the dilation only where the soft input doesn't fall off steeply
+    gradient_dilate: bool,
+    // Rebuild the odd rows of each input from the even field
+    deinterlace: bool,
+    flood_source: FloodSource,
+    // Foreground-area ratio to the history's mean beyond which a frame gets no weight (0 = off)
+    outlier_ratio: f32,
+    // Give the isolated component's edge pixels supersampled fractional coverage
+    aa_isolate: bool,
+    // Only erode parts narrower than this many pixels, instead of everywhere (0 = off)
+    max_neck: f32,
+    // Threshold used while the history fills up, easing to `threshold` (None = off)
+    warmup_threshold: Option<f32>,
+    // Never leave the clicked pixel itself below its input value
+    guarantee_click: bool,
+    // Confine processing to the last selection's box grown by the margin
+    auto_roi: bool,
+    roi_margin: usize,
+    // Pixels of replicated edge added around the frame for the morphology
+    edge_padding: usize,
+    // Bits per value history frames are stored with (8, 16, or 32 = f32)
+    history_bits: u8,
+    // Block size and search radius for aligning history frames before averaging (0 = off)
+    block_match: (usize, usize),
+    // Largest ratio of dilated to isolated area (0 = uncapped)
+    max_growth: f32,
+    // Times the isolate-and-clean pipeline runs per frame
+    passes: usize,
+    // Cut the subject's shape out of the RGBA output instead of the subject
+    knockout: bool,
+    // Alpha every background pixel of the returned masks is set to (None = off)
+    background_floor: Option<f32>,
+    // Share of the newest frame in the mean, the rest split over the older frames (None = by weight)
+    current_frame_weight: Option<f32>,
+    // Most pixels the clicked component may keep before dilation (0 = no cap)
+    max_selection_area: u32,
+    // Align older frames by rotation and scale about the centroid before averaging
+    logpolar_smoothing: bool,
+    debug_layers: bool,
+    // Halvings of the coarse grid the isolating fill starts on (0 = plain fill)
+    pyramid_levels: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            border_mode: BorderMode::Constant,
+            spatial_median: false,
+            alpha_gamma: 1.0,
+            alpha_curve: Vec::new(),
+            fadeout_frames: 0,
+            erode_radius: 5,
+            dilate_radius: 5,
+            auto_gain: false,
+            threshold: 0.5,
+            dilate_after_isolate: true,
+            edge_smooth: false,
+            bbox_smoothing: 0.0,
+            binary_input: false,
+            result_blend: 1.0,
+            pixel_aspect: 1.0,
+            element_rotation: 0.0,
+            element_shape: ElementShape::Circle,
+            element_length: 0,
+            confidence_floor: 1.1,
+            auto_reset: false,
+            auto_reset_sensitivity: 0.0,
+            preserve_thin: false,
+            selection_locked: false,
+            smoothing_ms: 0.0,
+            erosion_enabled: true,
+            deghost: 0.0,
+            output_layout: OutputLayout::RowMajor,
+            protect_click_radius: 0,
+            hard_output: false,
+            max_components: 0,
+            grayscale_dilate: false,
+            feather_linear: true,
+            process_scale: 1,
+            downscale_max: false,
+            keep_averaged: false,
+            max_hole_area: 0,
+            robust_seed: false,
+            on_mismatch: MismatchMode::ReturnInput,
+            erosion_guided_feather: false,
+            localized_smoothing: false,
+            click_threshold_relax: false,
+            keep_isolated: false,
+            temporal_mode: TemporalMode::Mean,
+            sanitize_input: false,
+            geodesic_limit: None,
+            gradient_dilate: false,
+            deinterlace: false,
+            flood_source: FloodSource::Eroded,
+            outlier_ratio: 0.0,
+            aa_isolate: false,
+            max_neck: 0.0,
+            warmup_threshold: None,
+            guarantee_click: false,
+            auto_roi: false,
+            roi_margin: 0,
+            edge_padding: 0,
+            history_bits: 32,
+            block_match: (0, 0),
+            max_growth: 0.0,
+            passes: 1,
+            knockout: false,
+            background_floor: None,
+            current_frame_weight: None,
+            max_selection_area: 0,
+            logpolar_smoothing: false,
+            debug_layers: false,
+            pyramid_levels: 0,
+        }
+    }
+}
+
 #[wasm_bindgen]
 pub struct SubjectRefiner {
     width: usize,
     height: usize,
-    // Store previous frames for temporal smoothing
-    history: Vec<Vec<f32>>,
+    // Store previous frames for temporal smoothing, with their averaging weights
+    history: Vec<Frame>,
+    history_weights: Vec<f32>,
     max_history: usize,
+    config: Config,
+    // Per-pixel fade-out state for `fadeout_frames`
+    fade: Vec<f32>,
+    // Pixels the flood fill must never cross (empty = no barrier)
+    barrier: Vec<u8>,
+    // Pixels (non-zero) the flood fill may enter; empty = unconstrained
+    flood_allowed: Vec<u8>,
+    // Whether the last final mask reached the first/last row or column
+    touches_border: bool,
+    // Frames completed by the current batch
+    batch_progress: usize,
+    // Last frame's input, thresholded average and eroded binary, for follow-ups
+    last_input: Vec<f32>,
+    last_binary: Vec<u8>,
+    last_eroded: Vec<u8>,
+    // Persistent per-pixel selection labels written by `add_selection`
+    selection_labels: Vec<u8>,
+    // Foreground area, bounding box [x0, y0, x1, y1) and row/column counts of the last selection
+    last_area: usize,
+    last_bbox: [usize; 4],
+    // Exponentially smoothed `[x0, y0, x1, y1]`, None while the selection is empty
+    smoothed_bbox: Option<[f32; 4]>,
+    last_profiles: Vec<u32>,
+    // Previous output, blended with by `result_blend`
+    last_final: Vec<f32>,
+    // Bitfield of events from the last refine (STATUS_* constants)
+    status: u32,
+    // Distinct components the last refine's clicks selected, and where the last one seeded
+    components_selected: usize,
+    last_seed: Option<(usize, usize)>,
+    // Normalized centroid of the last selection and its change from the frame before
+    last_centroid: Option<(f32, f32)>,
+    velocity: [f32; 2],
+    // Centroids of the recent consecutive non-empty frames, oldest first (at most `max_history`)
+    centroid_track: Vec<(f32, f32)>,
+    // Bounding-box diagonal of the last selection relative to the frame before
+    scale_delta: f32,
+    // Pixels whose selection flipped since the frame before
+    churn_area: u32,
+    // Churn as a fraction of the selection for the last few frames, oldest first
+    flicker: Vec<f32>,
+    // Mean input confidence over the last result's soft edge band
+    edge_confidence: f32,
+    // Brush corrections applied to every result: 1 = force in, 2 = force out (empty = none)
+    paint: Vec<u8>,
+    // Structuring-element offsets per radius for the current shape, and how many were built
+    kernel_cache: RefCell<Vec<(i32, Kernel)>>,
+    kernel_builds: Cell<u32>,
+    // Gamma encode and decode tables for the edge blur, and how often they were built
+    feather_lut: RefCell<Vec<[f32; 2]>>,
+    feather_lut_builds: Cell<u32>,
+    // Buffers kept for `last_averaged` and `last_isolated`
+    last_averaged: Vec<f32>,
+    last_isolated: Vec<u8>,
+    // `[mode, low, high, applied]` threshold of the last refine (empty before the first)
+    last_threshold: Vec<f32>,
+    // Selection layers (0 is the default one); the active layer's state lives in the fields above
+    layers: Vec<Layer>,
+    active_layer: usize,
+    // Box the last frame was confined to under `auto_roi`, and the dilate radius it used
+    roi: Option<[usize; 4]>,
+    applied_dilate: i32,
+    chunked: Option<ChunkedRefine>,
+    // Stacked stage planes of the last frame for `export_layers` (empty unless `debug_layers`)
+    last_layers: Vec<f32>,
 }
 
 #[wasm_bindgen]
@@ -23,173 +512,6145 @@ impl SubjectRefiner {
             width,
             height,
             history: Vec::new(),
+            history_weights: Vec::new(),
             max_history,
+            config: Config::default(),
+            fade: Vec::new(),
+            barrier: Vec::new(),
+            flood_allowed: Vec::new(),
+            touches_border: false,
+            batch_progress: 0,
+            last_input: Vec::new(),
+            last_binary: Vec::new(),
+            last_eroded: Vec::new(),
+            selection_labels: Vec::new(),
+            last_area: 0,
+            last_bbox: [0; 4],
+            smoothed_bbox: None,
+            last_profiles: Vec::new(),
+            last_final: Vec::new(),
+            status: 0,
+            components_selected: 0,
+            last_seed: None,
+            last_centroid: None,
+            velocity: [0.0; 2],
+            centroid_track: Vec::new(),
+            scale_delta: 1.0,
+            churn_area: 0,
+            flicker: Vec::new(),
+            edge_confidence: 0.0,
+            paint: Vec::new(),
+            kernel_cache: RefCell::new(Vec::new()),
+            kernel_builds: Cell::new(0),
+            feather_lut: RefCell::new(Vec::new()),
+            feather_lut_builds: Cell::new(0),
+            last_averaged: Vec::new(),
+            last_isolated: Vec::new(),
+            last_threshold: Vec::new(),
+            layers: vec![Layer::default()],
+            active_layer: 0,
+            roi: None,
+            applied_dilate: 0,
+            chunked: None,
+            last_layers: Vec::new(),
+        }
+    }
+
+    /// Select how the frame edges are handled:
+    /// 0 = constant (out-of-bounds is background), 1 = horizontal wrap for 360° video.
+    /// Unknown values fall back to constant.
+    pub fn set_border_mode(&mut self, mode: u32) {
+        self.config.border_mode = match mode {
+            1 => BorderMode::Wrap,
+            _ => BorderMode::Constant,
+        };
+    }
+
+    /// Pad the frame by `pixels` on every side, replicating the edge values,
+    /// before thresholding, morphology and flood fill, and crop back
+    /// afterwards. Erosion treats out-of-bounds as background, so a subject
+    /// running off the frame otherwise loses a band along the edge that the
+    /// dilation can't restore; with at least the erode radius of padding its
+    /// border survives. Only the stateful refine calls pad (not
+    /// `refine_with_prev`), and not in wrap border mode. 0 (default) is off.
+    pub fn set_edge_padding(&mut self, pixels: usize) {
+        self.config.edge_padding = pixels;
+    }
+
+    /// Memory order of the masks returned by `refine_mask` and the other
+    /// stateful refine calls (multi-click, tiles, batches): 0 = row-major
+    /// (default), 1 = column-major, i.e. transposed so pixel `(x, y)` is at
+    /// `x * height + y`, for GPU code that expects it. Inputs stay row-major,
+    /// and the bounding box, shape stats and every other query keep reporting
+    /// in the original frame. `refine_with_prev` always returns row-major,
+    /// since its result is fed back in as `prev_mask`. Unknown values fall
+    /// back to row-major.
+    pub fn set_output_layout(&mut self, layout: u32) {
+        self.config.output_layout = match layout {
+            1 => OutputLayout::ColumnMajor,
+            _ => OutputLayout::RowMajor,
+        };
+    }
+
+    /// What the refine calls return when the input is not `width * height`
+    /// long: 0 = the input unchanged (default, as before), 1 = all zeros of the
+    /// expected size, 2 = all zeros and `last_status` reports
+    /// `STATUS_SIZE_MISMATCH` (bit 1; `refine_with_prev` can't record status
+    /// and just returns zeros). Calls that return an empty vec on bad input
+    /// still do. Unknown values fall back to 0.
+    pub fn set_on_mismatch(&mut self, mode: u32) {
+        self.config.on_mismatch = match mode {
+            1 => MismatchMode::ReturnEmpty,
+            2 => MismatchMode::Flag,
+            _ => MismatchMode::ReturnInput,
+        };
+    }
+
+    /// Bob-deinterlace each incoming mask so interlaced sources don't leave
+    /// comb artefacts in the selection: the top field (even rows, as in
+    /// top-field-first video) is kept and every odd row is rebuilt as the mean
+    /// of the rows above and below it (the last row copies the one above).
+    /// Runs right after `set_sanitize_input` and before the other input
+    /// filters. Halves the vertical detail, so leave it off for progressive
+    /// sources. Default off.
+    pub fn set_deinterlace(&mut self, on: bool) {
+        self.config.deinterlace = on;
+    }
+
+    /// Run a 3x3 median over each incoming mask before it enters the history.
+    /// Knocks out isolated salt-and-pepper pixels while keeping edges sharp,
+    /// and is cheaper than a morphological opening.
+    pub fn set_spatial_median(&mut self, on: bool) {
+        self.config.spatial_median = on;
+    }
+
+    /// Apply `alpha.powf(gamma)` to the final mask. 1.0 is a no-op, values
+    /// below 1 (e.g. 0.45) make soft edges more opaque and values above 1
+    /// (e.g. 2.2) make them more transparent. Clamped to 0.1..=10.
+    pub fn set_alpha_gamma(&mut self, gamma: f32) {
+        self.config.alpha_gamma = if gamma.is_finite() { gamma.clamp(0.1, 10.0) } else { 1.0 };
+    }
+
+    /// Remap confidence through a user curve: `lut` holds N >= 2 samples of
+    /// the curve at 0, 1/(N-1), ..., 1 (256 is typical), and a value `v` maps
+    /// to `lut` at `v * (N - 1)`, linearly interpolated, with `v` clamped to
+    /// 0-1. The temporal average is remapped before thresholding (so the
+    /// threshold applies to the curve's output, as does `last_averaged`), and
+    /// so are the soft values of the final mask, before `set_alpha_gamma`.
+    /// Gamma, sigmoid or any S-curve can be expressed this way. Returns false
+    /// and keeps the current curve if `lut` has fewer than 2 entries or a
+    /// non-finite one.
+    pub fn set_alpha_curve(&mut self, lut: &[f32]) -> bool {
+        if lut.len() < 2 || lut.iter().any(|v| !v.is_finite()) {
+            return false;
+        }
+        self.config.alpha_curve = lut.to_vec();
+        true
+    }
+
+    /// Remove the alpha curve, restoring the identity mapping.
+    pub fn clear_alpha_curve(&mut self) {
+        self.config.alpha_curve.clear();
+    }
+
+    /// Fade out pixels that drop out of the selection linearly over `n` frames
+    /// instead of removing them at once, so a subject leaving the frame does
+    /// not pop. 0 disables it.
+    ///
+    /// This runs on the final mask, after the history-window mean: the window
+    /// still decides how quickly new pixels appear, while the fade only
+    /// lengthens how long vanished ones linger.
+    pub fn set_fadeout_frames(&mut self, n: usize) {
+        self.config.fadeout_frames = n;
+        self.fade.clear();
+    }
+
+    /// Mark pixels (non-zero) that the flood fill treats as background no matter
+    /// what the mask says, e.g. a user-drawn cut line between two objects that
+    /// erosion can't separate. Returns false and keeps the current barrier if
+    /// the length is not `width * height`.
+    pub fn set_barrier(&mut self, barrier: &[u8]) -> bool {
+        if barrier.len() != self.width * self.height {
+            return false;
+        }
+        self.barrier = barrier.to_vec();
+        true
+    }
+
+    /// Remove the flood-fill barrier.
+    pub fn clear_barrier(&mut self) {
+        self.barrier.clear();
+    }
+
+    /// Confine the flood fill to a user-painted region: pixels that are zero
+    /// in `allowed` act as barriers, so a rough scribble around the subject
+    /// selects only the part of it inside the scribble. Combines with
+    /// `set_barrier`. Returns false and keeps the current constraint if the
+    /// length is not `width * height`.
+    pub fn set_flood_constraint(&mut self, allowed: &[u8]) -> bool {
+        if allowed.len() != self.width * self.height {
+            return false;
+        }
+        self.flood_allowed = allowed.to_vec();
+        true
+    }
+
+    /// Remove the flood-fill constraint.
+    pub fn clear_flood_constraint(&mut self) {
+        self.flood_allowed.clear();
+    }
+
+    /// Stamp a disk of `brush_radius` pixels at `(x, y)` (normalized like a
+    /// click) into a persistent correction layer: `add` forces those pixels to
+    /// 1.0 in every later result, otherwise they are forced to 0.0. Later
+    /// strokes overwrite earlier ones. The layer is applied to the output
+    /// after isolation and does not enter the history, so painted pixels are
+    /// not temporally smoothed and stay where they were painted rather than
+    /// following the subject; result blending and fade-out still act on them.
+    pub fn paint(&mut self, x: f32, y: f32, brush_radius: usize, add: bool) {
+        let size = self.width * self.height;
+        let Some((cx, cy)) = self.click_to_pixel(x, y) else {
+            return;
+        };
+        if self.paint.len() != size {
+            self.paint = vec![0; size];
+        }
+        let r = brush_radius as i32;
+        let h = self.height as i32;
+        for dy in -r..=r {
+            for dx in -r..=r {
+                let ny = cy as i32 + dy;
+                if dx * dx + dy * dy > r * r || ny < 0 || ny >= h {
+                    continue;
+                }
+                if let Some(nx) = self.wrap_x(cx as i32 + dx) {
+                    self.paint[ny as usize * self.width + nx as usize] = if add { 1 } else { 2 };
+                }
+            }
+        }
+    }
+
+    /// Remove all painted corrections.
+    pub fn clear_paint(&mut self) {
+        self.paint.clear();
+    }
+
+    /// True if the last refined selection has a foreground pixel on the first
+    /// or last row or column, i.e. the subject is probably cut off by the frame.
+    /// False when the selection is empty.
+    pub fn touches_border(&self) -> bool {
+        self.touches_border
+    }
+
+    /// Radius of the erosion that breaks bridges between touching objects (default 5).
+    pub fn set_erode_radius(&mut self, radius: i32) {
+        self.config.erode_radius = radius.max(0);
+    }
+
+    /// Radius of the dilation that restores edges after isolation (default 5).
+    /// When it differs from the erode radius the subject is net-shrunk (smaller)
+    /// or net-grown (larger), which helps with models that consistently over-
+    /// or under-segment.
+    pub fn set_dilate_radius(&mut self, radius: i32) {
+        self.config.dilate_radius = radius.max(0);
+    }
+
+    /// Normalize model confidence per frame: the averaged mask is rescaled so
+    /// its 99th percentile (estimated from a 256-bin histogram) maps to 1.0
+    /// before thresholding. Keeps selections stable when the model's output
+    /// range drifts with lighting. Assumes some foreground is present (at least
+    /// ~1% of the frame); frames whose percentile is below 0.05 are left as-is
+    /// rather than amplifying pure background noise.
+    pub fn set_auto_gain(&mut self, on: bool) {
+        self.config.auto_gain = on;
+    }
+
+    /// Confidence above which an averaged pixel counts as foreground (default 0.5).
+    pub fn set_threshold(&mut self, threshold: f32) {
+        self.config.threshold = threshold;
+    }
+
+    /// Suppress the faint trail fast motion leaves in the temporal average:
+    /// wherever the latest frame is near zero (at most 0.1, the same cut-off
+    /// below which soft values are never re-applied), the averaged value is
+    /// pulled toward it by `strength * (averaged - latest)` before
+    /// thresholding. The trail then stops bridging to other objects or
+    /// stretching the selection, while pixels the model still sees are
+    /// smoothed as before. 0 (default) is off, 1 drops the trail entirely.
+    pub fn set_deghost(&mut self, strength: f32) {
+        self.config.deghost = if strength.is_finite() { strength.clamp(0.0, 1.0) } else { 0.0 };
+    }
+
+    /// Align the history to the current frame before averaging, so a moving
+    /// subject doesn't smear into a ghost. The frame is cut into
+    /// `block x block` tiles, and each tile containing part of the current
+    /// mask's boundary is matched against every older frame by searching
+    /// shifts of up to `search` pixels for the smallest sum of absolute
+    /// differences; that frame's tile is then read from the best shift for the
+    /// average. Tiles away from the boundary, and tiles whose best match still
+    /// differs by more than 0.1 per pixel on average, keep their original
+    /// position, so the smoothing falls back to the plain average where no
+    /// good match exists. `search` must cover the subject's motion across the
+    /// whole history window. Costs about `(2 * search + 1)²` comparisons per
+    /// boundary pixel and stored frame. 0 for either value (default) is off.
+    pub fn set_block_match_align(&mut self, block: usize, search: usize) {
+        self.config.block_match = (block, search);
+    }
+
+    /// Give the incoming frame a fixed share `w` (0-1) of the temporal mean
+    /// instead of weighting it like every other stored frame: the older frames
+    /// are averaged on their own (by their weights) and blended with the
+    /// current one as `(1 - w) * older + w * current`, a prediction-then-
+    /// correction scheme where `w` trades latency against stability directly.
+    /// With `n` older frames of equal weight, `w = 1/(n+1)` reproduces the
+    /// default mean; 0 holds the prediction and 1 disables smoothing. The
+    /// first frame, and a frame rejected by `set_outlier_rejection`, are
+    /// handled as before. Only affects the mean temporal mode. A negative or
+    /// non-finite `w` restores the default weighting.
+    pub fn set_current_frame_weight(&mut self, w: f32) {
+        self.config.current_frame_weight = (w.is_finite() && w >= 0.0).then(|| w.min(1.0));
+    }
+
+    /// Experimental rotation- and scale-tolerant smoothing for spinning
+    /// subjects: every older frame and the current one are resampled onto a
+    /// 128 x 64 log-polar grid around the previous selection's centroid, where
+    /// a rotation is an angular shift and a scale change a radial one. The
+    /// shift that best matches the current frame (any angle, up to 4 radial
+    /// bins either way) is found by brute force, and the older frame is warped
+    /// by that rotation and scale back in image space before it enters the
+    /// average; the current frame is never resampled. Costs about ten million
+    /// multiply-adds per stored frame, on top of one bilinear warp. The warp
+    /// softens older frames slightly, and a wrong centroid (off-centre
+    /// rotation, translation, several subjects) produces smeared ghosts, so it
+    /// only suits a roughly centred subject rotating in place. Takes the place
+    /// of `set_block_match_align` while on; needs a previous selection.
+    pub fn set_logpolar_smoothing(&mut self, on: bool) {
+        self.config.logpolar_smoothing = on;
+    }
+
+    /// Run thresholding, erosion, isolation and dilation `n` times per frame
+    /// (at least 1, the default). Each pass after the first starts from the
+    /// temporal average restricted to the previous pass's result intersected
+    /// with the original thresholded mask, so stray regions the first opening
+    /// kept attached are cut back and the boundary settles, like an iterated
+    /// reconstruction. Most of the change happens in the second pass; beyond
+    /// 2-3 passes the result rarely moves. Each pass costs another full run of
+    /// the morphology and flood fill.
+    pub fn set_passes(&mut self, n: usize) {
+        self.config.passes = n.max(1);
+    }
+
+    /// Keep the edge-restoring dilation from ballooning the selection into the
+    /// background: the dilate radius is reduced, per frame, to the largest
+    /// radius whose dilated region is at most `ratio` times the area of the
+    /// isolated component before dilation. The soft re-application then only
+    /// clips further (pixels the model scores 0 stay out), so the final area
+    /// stays within the cap. `last_dilate_radius` reports the radius used.
+    /// 0 (default) leaves the radius as configured; ratios below 1 allow no
+    /// dilation at all.
+    pub fn set_max_growth(&mut self, ratio: f32) {
+        self.config.max_growth = if ratio.is_finite() { ratio.max(0.0) } else { 0.0 };
+    }
+
+    /// Dilate radius the last refined frame actually used, after
+    /// `set_max_growth` (the configured radius if uncapped; 0 when the frame
+    /// wasn't dilated).
+    pub fn last_dilate_radius(&self) -> i32 {
+        self.applied_dilate
+    }
+
+    /// Exempt a disk of radius `r` pixels around the click from erosion: inside
+    /// it the thresholded mask is kept as-is, so the seed survives for the
+    /// flood fill even where erosion would have deleted exactly the spot that
+    /// was clicked (a thin arm, a narrow object). Applies to every click of
+    /// `refine_multi`. 0 (default) disables it.
+    pub fn set_protect_click_radius(&mut self, r: usize) {
+        self.config.protect_click_radius = r;
+    }
+
+    /// Make sure the clicked pixel is selected: after the pipeline (and hole
+    /// filling, before painted corrections) its final value is raised to at
+    /// least the model's input value there, so clicking the subject never
+    /// returns a result that is empty at exactly that spot because erosion and
+    /// dilation didn't give it back. Only that single pixel is touched (the
+    /// first click's, with several `refine_multi` clicks), and nothing while
+    /// `lock_selection` ignores the clicks; to keep the neighbourhood of the
+    /// click as well, combine it with `set_protect_click_radius`. Default off.
+    pub fn set_guarantee_click(&mut self, on: bool) {
+        self.config.guarantee_click = on;
+    }
+
+    /// Skip the erosion and its matching dilation, flood-filling the thresholded
+    /// binary directly, for scenes with one subject and nothing touching it:
+    /// the edges are no longer chewed and regrown. Without the erosion nothing
+    /// breaks bridges, so a click may also grab objects touching the subject.
+    /// The dilate flag, radii and element shape are then unused. Default on.
+    pub fn set_erosion_enabled(&mut self, on: bool) {
+        self.config.erosion_enabled = on;
+    }
+
+    /// When off, skip the dilation after isolation and re-apply the soft input
+    /// directly within the isolated component, so the selection is not grown.
+    /// The component is still eroded, so pair this with a small (or zero)
+    /// erode radius to keep the model's own soft boundary. Default on.
+    pub fn set_dilate_after_isolate(&mut self, on: bool) {
+        self.config.dilate_after_isolate = on;
+    }
+
+    /// Round off the stair-stepped binary boundary left by morphology before
+    /// the soft values are re-applied. Each pixel takes the majority of its
+    /// 5x5 neighbourhood, which only moves pixels in the transition band; the
+    /// interior and far background stay as they are. This changes the shape of
+    /// the selection, not its softness.
+    pub fn set_edge_smooth(&mut self, on: bool) {
+        self.config.edge_smooth = on;
+    }
+
+    /// Track the subject with a region of interest: after each refine the ROI
+    /// becomes the selection's bounding box grown by `margin` pixels (clamped
+    /// to the frame), and the next frame's thresholding, morphology and flood
+    /// fill only run inside it, which is faster for a small subject and keeps
+    /// unrelated objects elsewhere out of the selection. The temporal average
+    /// still covers the full frame. When the result reaches the ROI's edge
+    /// (the subject moving out), comes out empty, or a click lands outside the
+    /// ROI, that frame is processed again at full frame. Keep `margin` above
+    /// the erode plus dilate radius and the subject's per-frame motion. Not
+    /// applied with the wrap border mode. Off by default.
+    pub fn set_auto_roi(&mut self, on: bool, margin: usize) {
+        self.config.auto_roi = on;
+        self.config.roi_margin = margin;
+        self.roi = None;
+    }
+
+    /// Run thresholding, morphology and flood fill on a frame downscaled by the
+    /// integer `scale` for speed (1, the default, is full resolution); the
+    /// selected region is scaled back up and the soft values re-applied at
+    /// full resolution. The erode, dilate and click-protect radii are divided
+    /// by `scale`. `mode` picks the downscale: 0 = area average (default),
+    /// 1 = max pooling. Averaging keeps the subject's size but a structure
+    /// thinner than the footprint averages below the threshold and vanishes;
+    /// max pooling marks a cell foreground if any pixel in its footprint is,
+    /// keeping thin features (hair, wires) at the cost of thickening every
+    /// edge by up to one cell.
+    pub fn set_process_scale(&mut self, scale: usize, mode: u32) {
+        self.config.process_scale = scale.max(1);
+        self.config.downscale_max = mode == 1;
+    }
+
+    /// Return `[proc_width, proc_height, out_width, out_height]`: the grid the
+    /// thresholding, morphology and flood fill actually run on under
+    /// `set_process_scale` (each side divided by the scale, rounded up) next to
+    /// the full output resolution. Equal pairs mean full-resolution processing;
+    /// a structure thinner than one processing cell may vanish.
+    pub fn processing_dimensions(&self) -> Vec<usize> {
+        let s = self.config.process_scale;
+        vec![self.width.div_ceil(s), self.height.div_ceil(s), self.width, self.height]
+    }
+
+    /// Fill holes in the selection, i.e. enclosed background regions that don't
+    /// reach the frame edge, smaller than `max_area` pixels with 1.0, while
+    /// larger ones (the middle of a donut, the gap under an arm) stay open.
+    /// Runs on the final mask before painted corrections. 0 (default) fills
+    /// nothing.
+    pub fn fill_holes_smaller_than(&mut self, max_area: u32) {
+        self.config.max_hole_area = max_area;
+    }
+
+    /// Antialias the isolated component's boundary by coverage sampling. For
+    /// each pixel on either side of the flood fill's edge the temporal average
+    /// is sampled bilinearly on a 4x4 grid inside the pixel, and the share of
+    /// samples above the threshold becomes its coverage (1 inside, 0 beyond
+    /// the edge). The coverage map goes through the dilation as a max filter
+    /// and multiplies the re-applied values, so even `set_hard_output` gets a
+    /// smooth edge. It shows most where the fill's edge is the threshold
+    /// contour (erosion off, or `set_flood_on(1)`). Costs 16 interpolations
+    /// per edge pixel plus a grayscale dilation; edge smoothing is skipped.
+    /// Default off.
+    pub fn set_aa_isolate(&mut self, on: bool) {
+        self.config.aa_isolate = on;
+    }
+
+    /// Make the edge-restoring dilation follow the model's soft boundary. The
+    /// region grows one pixel ring at a time (8-neighbours, up to the dilate
+    /// radius and never outside the usual structuring element), and a step is
+    /// refused where the input confidence drops by more than 0.25 per pixel in
+    /// the direction of growth. The slope is the input's gradient from central
+    /// differences, projected on the step. Growth carries on where the soft
+    /// mask stays confident and stops early at a sharp fall-off; in flat
+    /// regions nothing is refused, so it matches the symmetric dilation.
+    /// Ignored with `set_grayscale_dilate`. Default off.
+    pub fn set_gradient_dilate(&mut self, on: bool) {
+        self.config.gradient_dilate = on;
+    }
+
+    /// Replace the binary dilation and soft re-application with a single
+    /// grayscale dilation: the soft input values are re-applied inside the
+    /// eroded, isolated component and then max-filtered with the dilation
+    /// element, so each grown pixel takes the strongest soft value around it
+    /// rather than its own, so the grown band carries the component's edge
+    /// values outward instead of the model's raw values there. Edge smoothing
+    /// does not apply, and the confidence floor uses the undilated component's
+    /// box. Default off.
+    pub fn set_grayscale_dilate(&mut self, on: bool) {
+        self.config.grayscale_dilate = on;
+    }
+
+    /// Treat `input_mask` as a hard mask: values are rounded to 0/1 at 0.5 on
+    /// the way in, the history average acts as a majority vote, and the result
+    /// is still a `Vec<f32>` but holds only 0.0 and 1.0 (soft re-application
+    /// and alpha gamma no longer apply).
+    pub fn set_binary_input(&mut self, on: bool) {
+        self.config.binary_input = on;
+    }
+
+    /// Run `sanitize` on every incoming mask before the other input filters
+    /// and the history see it, so one NaN or infinity from the model can't
+    /// poison the temporal average. Default off.
+    pub fn set_sanitize_input(&mut self, on: bool) {
+        self.config.sanitize_input = on;
+    }
+
+    /// Stop the labeling of `keep_largest_n` and `all_component_masks` after `n`
+    /// components (in row-major order of their first pixel) to bound the cost
+    /// on noisy masks; later ones count as background, so small legitimate
+    /// components may be dropped. Hole filling and contours are not capped.
+    /// Default 0 (unlimited).
+    pub fn set_max_components(&mut self, n: usize) {
+        self.config.max_components = n;
+    }
+
+    /// Speed up the isolating flood fill on very large frames with a pyramid:
+    /// the fill map is reduced `levels` times by 2 (a coarse pixel is set only
+    /// when its whole block is fillable), the clicked component is filled on
+    /// that coarse grid and its blocks are marked at once, and a full
+    /// resolution fill then only refines the boundary, within the coarse
+    /// component grown by one block. The per-pixel queue work shrinks to the
+    /// coarse area plus the boundary band. The result matches the plain fill
+    /// except where a structure thinner than a coarse block reaches more than
+    /// a block beyond the coarse component: it is cut off there, along with
+    /// anything attached only through it (so a thin bridge no longer joins two
+    /// objects). Typical edges come out identical or within a pixel or two.
+    /// Falls back to the plain fill in wrap mode, with a geodesic limit, or
+    /// when the click is not on a full coarse block. 0 (default) is off;
+    /// clamped to at most 6.
+    pub fn set_pyramid_fill(&mut self, levels: usize) {
+        self.config.pyramid_levels = levels.min(6);
+    }
+
+    /// Cap the clicked selection at `px` pixels: when the isolated component
+    /// is larger, only the `px` pixels closest to the click by geodesic
+    /// distance (4-connected steps inside the component, from the pixel the
+    /// fill started at) are kept, so an accidental whole-frame selection stays
+    /// bounded. Pixels at equal distance are kept in breadth-first discovery
+    /// order: earlier clicks first, then left, right, up, down neighbours. The
+    /// cap applies before dilation, which regrows the edge as usual, and only
+    /// to click-seeded frames. 0 (default) is off.
+    pub fn set_max_selection_area(&mut self, px: u32) {
+        self.config.max_selection_area = px;
+    }
+
+    /// Emit a hard mask: 1.0 everywhere inside the dilated isolated region and
+    /// 0.0 outside, ignoring the soft `input_mask` values, for sprite and game
+    /// pipelines that want no partial alpha. This disables the soft edge
+    /// re-application (and so alpha gamma) as well as `set_edge_smooth`, which
+    /// would otherwise reshape the region. Result blending and fade-out still
+    /// produce intermediate values if enabled.
+    pub fn set_hard_output(&mut self, on: bool) {
+        self.config.hard_output = on;
+    }
+
+    /// Crossfade each result with the previous output:
+    /// `factor * new + (1 - factor) * previous`. Smooths the visual jump when the
+    /// selection changes (e.g. the user clicks a slightly different spot), at
+    /// the result level rather than over input frames. 1.0 (default) disables
+    /// it; clamped to 0-1.
+    pub fn set_result_blend(&mut self, factor: f32) {
+        self.config.result_blend = factor.clamp(0.0, 1.0);
+    }
+
+    /// Correct the structuring element for non-square pixels. `ratio` is the
+    /// height of a pixel relative to its width, and the element's membership
+    /// test becomes `dx² + (dy·ratio)² <= r²` with the radius in horizontal
+    /// pixels, so the element is circular in physical space on anamorphic
+    /// footage. 1.0 (default) is square pixels; clamped to 0.1-10.
+    pub fn set_pixel_aspect(&mut self, ratio: f32) {
+        self.config.pixel_aspect = if ratio.is_finite() { ratio.clamp(0.1, 10.0) } else { 1.0 };
+        self.kernel_cache.get_mut().clear();
+    }
+
+    /// Choose the structuring element for erosion and dilation:
+    /// 0 = circle (default), 1 = square, 2 = horizontal line, 3 = vertical line.
+    /// Circles and squares use the erode/dilate radii. Lines are one pixel
+    /// thick and centred, so `length` is rounded up to odd (0 means
+    /// `2 * radius + 1`); opening with a line keeps structures running along
+    /// it (fences, flagpoles, text lines) and removes thin noise across it.
+    /// Unknown shapes fall back to the circle.
+    pub fn set_element_shape(&mut self, shape: u32, length: usize) {
+        self.config.element_shape = match shape {
+            1 => ElementShape::Square,
+            2 => ElementShape::HorizontalLine,
+            3 => ElementShape::VerticalLine,
+            _ => ElementShape::Circle,
+        };
+        self.config.element_length = length;
+        self.kernel_cache.get_mut().clear();
+    }
+
+    /// Rotate the structuring element by `radians` (clockwise on screen, y
+    /// pointing down) for tilted or Dutch-angle footage, so square and line
+    /// elements line up with the subject's own axes. Only the element is
+    /// rotated, not the frame: nothing is resampled, and the rotated shape is
+    /// rasterized onto the pixel grid, so a line at an odd angle becomes a
+    /// staircase one pixel thick and a rotated square reaches up to √2 times
+    /// the radius along the axes. Circles are unaffected. 0 (default) keeps
+    /// the upright element.
+    pub fn set_rotation(&mut self, radians: f32) {
+        self.config.element_rotation = if radians.is_finite() { radians.rem_euclid(std::f32::consts::TAU) } else { 0.0 };
+        self.kernel_cache.get_mut().clear();
+    }
+
+    /// Force any pixel whose input confidence is at least `v` into the final
+    /// mask, even if erosion cut it off from the flood-filled component, as
+    /// long as it lies inside the isolated component's bounding box before
+    /// dilation. Rescues high-confidence detail the morphology dropped.
+    /// Default 1.1 (disabled).
+    pub fn set_confidence_floor(&mut self, v: f32) {
+        self.config.confidence_floor = v;
+    }
+
+    /// Break bridges only at narrow necks: instead of eroding everything by
+    /// the erode radius, parts of the thresholded mask narrower than
+    /// `max_neck` pixels are removed and wider regions are kept whole. A pixel
+    /// survives when it is within `max_neck / 2` of a pixel whose distance to
+    /// the background is more than `max_neck / 2`, i.e. it is covered by a disk
+    /// of diameter `max_neck` inside the mask. Touching objects come apart at
+    /// their thin connections while the bulk shape is preserved. Thin limbs
+    /// count as necks too and are dropped from the fill like with erosion.
+    /// Since the bulk isn't shrunk, pair this with a small dilate radius.
+    /// Follows `set_erosion_enabled`. 0 (default) uses the uniform erosion.
+    pub fn set_neck_erosion(&mut self, max_neck: f32) {
+        self.config.max_neck = if max_neck.is_finite() { max_neck.max(0.0) } else { 0.0 };
+    }
+
+    /// Let erosion keep thin structures instead of deleting them: the one-pixel
+    /// skeleton of the thresholded mask is added back to the eroded binary, so
+    /// limbs narrower than the erosion survive (end points and branch points
+    /// included) and are regrown by the dilation, while flat areas are still
+    /// eroded as usual. Thin bridges between objects are kept as well, so pair
+    /// this with a barrier where erosion alone was separating two subjects.
+    pub fn set_preserve_thin(&mut self, on: bool) {
+        self.config.preserve_thin = on;
+    }
+
+    /// Choose what the flood fill (and the locked or anchored selection)
+    /// computes connectivity on: 0 = the eroded binary (default), where the
+    /// erosion has broken thin bridges so touching objects come apart, but a
+    /// click on the eroded-away rim has to snap inward; 1 = the thresholded
+    /// binary before erosion, so nothing at the rim is lost but objects joined
+    /// by a bridge are selected together; 2 = a tolerant soft mask, the
+    /// average above half the threshold, which also connects through faint
+    /// areas and merges the most. With 1 or 2 the dilation afterwards still
+    /// grows the result. Unknown values fall back to 0.
+    pub fn set_flood_on(&mut self, source: u32) {
+        self.config.flood_source = match source {
+            1 => FloodSource::Binary,
+            2 => FloodSource::Soft,
+            _ => FloodSource::Eroded,
+        };
+    }
+
+    /// Let a click seed from the current frame when the eroded temporal average
+    /// has nothing at that spot (e.g. the first frame after a seek, when one
+    /// noisy frame is all the history there is): if the raw input at the click
+    /// is at least 0.9, its confident region around the click is added to the
+    /// eroded map before the flood fill. That region isn't eroded, so it can
+    /// still bridge to a neighbour. Default off.
+    pub fn set_robust_seed(&mut self, on: bool) {
+        self.config.robust_seed = on;
+    }
+
+    /// Confine temporal smoothing to the subject: the average over the history
+    /// window is only used within the previous selection grown by the dilate
+    /// radius (at least 1px), and everywhere else the latest frame is used
+    /// as-is, so flickering background objects don't bleed into the average
+    /// next to the subject. It needs a previously isolated frame; until there
+    /// is one (first frame, after `reset`, or an empty selection) the whole
+    /// frame is averaged as usual. Default off.
+    pub fn set_localized_smoothing(&mut self, on: bool) {
+        self.config.localized_smoothing = on;
+    }
+
+    /// Make faint subjects selectable: when a click finds no foreground within
+    /// the flood fill's 20px search after thresholding and erosion, the
+    /// threshold is retried at 3/4, 1/2 and 1/4 of its value and the first
+    /// component that forms near the click at the lowered level is isolated.
+    /// Only that component uses the relaxed threshold; the rest of the frame
+    /// keeps the configured one. Default off.
+    pub fn set_click_threshold_relax(&mut self, on: bool) {
+        self.config.click_threshold_relax = on;
+    }
+
+    /// Clear the temporal state (history, fade-out and the previous result), e.g.
+    /// at a cut or seek, so old frames don't ghost into the new scene.
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.history_weights.clear();
+        self.fade.clear();
+        self.last_final.clear();
+        self.last_centroid = None;
+        self.velocity = [0.0; 2];
+        self.centroid_track.clear();
+        self.scale_delta = 1.0;
+        self.churn_area = 0;
+        self.flicker.clear();
+        self.last_layers.clear();
+        self.smoothed_bbox = None;
+        self.edge_confidence = 0.0;
+        self.roi = None;
+    }
+
+    /// Restore every tunable setting to its default, as if freshly constructed,
+    /// while keeping the frame size, `max_history` and all per-frame state:
+    /// history, the previous result and its stats, batch progress, the
+    /// barrier, painted corrections and selection labels.
+    pub fn reset_config(&mut self) {
+        self.config = Config::default();
+        // Kernels are built for the element shape, which is back to the default
+        self.kernel_cache.get_mut().clear();
+    }
+
+    /// Detect scene cuts and `reset` automatically: when the mean absolute
+    /// difference between the incoming mask and the previous one exceeds
+    /// `sensitivity` (0-1, e.g. 0.2), history is flushed before the new frame
+    /// is added and `last_status` reports `STATUS_AUTO_RESET` (bit 0).
+    pub fn set_auto_reset(&mut self, on: bool, sensitivity: f32) {
+        self.config.auto_reset = on;
+        self.config.auto_reset_sensitivity = sensitivity;
+    }
+
+    /// The thresholding actually applied to the last refined frame, as
+    /// `[mode, low, high, applied]`: `mode` is 0 for the fixed threshold and 1
+    /// when auto gain rescaled the average first; `high` is the configured
+    /// threshold and `low` the lowest one any pixel was classified at (below
+    /// `high` only when `set_click_threshold_relax` had to relax it), both on
+    /// the (rescaled) average; `applied` is `high` in the units of the raw
+    /// temporal average, i.e. divided by the auto gain. Empty before the first
+    /// frame.
+    pub fn effective_threshold(&self) -> Vec<f32> {
+        self.last_threshold.clone()
+    }
+
+    /// Bitfield of events from the last refine:
+    /// bit 0 = history was auto-reset at a detected scene cut,
+    /// bit 1 = the input had the wrong length (see `set_on_mismatch`),
+    /// bit 2 = the frame was rejected as an outlier (see `set_outlier_rejection`).
+    pub fn last_status(&self) -> u32 {
+        self.status
+    }
+
+    /// Bitfield of the optional stages the current configuration runs, for
+    /// debugging a surprising output. The assignments are stable; new stages
+    /// only ever take new bits:
+    /// bit 0 = erosion (`set_erosion_enabled` and a radius above 0),
+    /// bit 1 = dilation (radius above 0),
+    /// bit 2 = spatial median (`set_spatial_median`),
+    /// bit 3 = edge feather (`set_edge_smooth`),
+    /// bit 4 = hole fill (`fill_holes_smaller_than`),
+    /// bit 5 = auto gain,
+    /// bit 6 = deghost,
+    /// bit 7 = scene-cut auto reset,
+    /// bit 8 = outlier rejection,
+    /// bit 9 = localized smoothing,
+    /// bit 10 = click threshold relax (hysteresis),
+    /// bit 11 = geodesic limit (reserved: `refine_mask_geodesic` only sets it per call),
+    /// bit 12 = neck erosion,
+    /// bit 13 = auto ROI,
+    /// bit 14 = multiple passes,
+    /// bit 15 = block-match alignment,
+    /// bit 16 = reduced processing scale,
+    /// bit 17 = deinterlace,
+    /// bit 18 = input sanitizing,
+    /// bit 19 = guaranteed click pixel,
+    /// bit 20 = growth cap (`set_max_growth`),
+    /// bit 21 = edge padding,
+    /// bit 22 = component limit (`set_max_components`),
+    /// bit 23 = result blend below 1,
+    /// bit 24 = fade-out frames,
+    /// bit 25 = background floor,
+    /// bit 26 = alpha gamma other than 1,
+    /// bit 27 = hard output,
+    /// bit 28 = log-polar smoothing,
+    /// bit 29 = alpha curve (`set_alpha_curve`).
+    pub fn active_stages(&self) -> u32 {
+        let stages = [
+            self.config.erosion_enabled && self.config.erode_radius > 0,
+            self.config.dilate_radius > 0,
+            self.config.spatial_median,
+            self.config.edge_smooth,
+            self.config.max_hole_area > 0,
+            self.config.auto_gain,
+            self.config.deghost > 0.0,
+            self.config.auto_reset,
+            self.config.outlier_ratio > 0.0,
+            self.config.localized_smoothing,
+            self.config.click_threshold_relax,
+            self.config.geodesic_limit.is_some(),
+            self.config.erosion_enabled && self.config.max_neck > 0.0,
+            self.config.auto_roi,
+            self.config.passes > 1,
+            self.config.block_match.0 > 0 && self.config.block_match.1 > 0,
+            self.config.process_scale > 1,
+            self.config.deinterlace,
+            self.config.sanitize_input,
+            self.config.guarantee_click,
+            self.config.max_growth > 0.0,
+            self.config.edge_padding > 0,
+            self.config.max_components > 0,
+            self.config.result_blend < 1.0,
+            self.config.fadeout_frames > 0,
+            self.config.background_floor.is_some(),
+            self.config.alpha_gamma != 1.0,
+            self.config.hard_output,
+            self.config.logpolar_smoothing,
+            !self.config.alpha_curve.is_empty(),
+        ];
+        stages.iter().enumerate().fold(0, |bits, (i, &on)| bits | ((on as u32) << i))
+    }
+
+    /// Repair a mask with invalid values: NaN and ±infinity become 0.0 and
+    /// everything else is clamped to 0-1.
+    pub fn sanitize(&self, mask: &[f32]) -> Vec<f32> {
+        mask.iter().map(|&v| if v.is_finite() { v.clamp(0.0, 1.0) } else { 0.0 }).collect()
+    }
+
+    /// Threshold `mask` with the configured settings and return the 0/1 binary,
+    /// without touching history or running the rest of the pipeline.
+    /// Returns an empty vec if the length is not `width * height`.
+    pub fn binarize(&self, mask: &[f32]) -> Vec<u8> {
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        self.threshold_mask(mask)
+    }
+
+    /// Intersection over union of `a` and `b` after binarizing both at
+    /// `threshold` (values above it count), e.g. a refined mask against ground
+    /// truth. 1.0 when both are empty, 0.0 when exactly one is, and 0.0 if the
+    /// lengths differ.
+    pub fn iou(&self, a: &[f32], b: &[f32], threshold: f32) -> f32 {
+        if a.len() != b.len() {
+            return 0.0;
+        }
+        let (mut inter, mut union) = (0usize, 0usize);
+        for (&x, &y) in a.iter().zip(b) {
+            let (x, y) = (x > threshold, y > threshold);
+            inter += (x && y) as usize;
+            union += (x || y) as usize;
+        }
+        if union == 0 { 1.0 } else { inter as f32 / union as f32 }
+    }
+
+    /// Symmetric Hausdorff distance in pixels between the boundaries of `a`
+    /// and `b` binarized at `threshold`: the larger of the two directed
+    /// distances, i.e. how far the worst boundary pixel of either mask is from
+    /// the other mask's boundary. Boundary pixels are foreground pixels with a
+    /// 4-neighbour background or on the frame edge. 0.0 when both are empty;
+    /// the frame diagonal when exactly one is or a length is not
+    /// `width * height`. Complements `iou`, which hides a small far-off error.
+    pub fn hausdorff(&self, a: &[f32], b: &[f32], threshold: f32) -> f32 {
+        self.boundary_distance(a, b, threshold, 1.0)
+    }
+
+    /// `hausdorff` with each directed distance taken at its 95th percentile
+    /// instead of the maximum, so a few stray boundary pixels don't dominate.
+    pub fn hausdorff_95(&self, a: &[f32], b: &[f32], threshold: f32) -> f32 {
+        self.boundary_distance(a, b, threshold, 0.95)
+    }
+
+    /// Shape statistics of the last selection: `[area, bbox_w, bbox_h, fill_ratio]`,
+    /// where `fill_ratio` is the foreground area divided by the bounding-box
+    /// area. All zeros when the selection is empty.
+    pub fn last_shape_stats(&self) -> Vec<f32> {
+        if self.last_area == 0 {
+            return vec![0.0; 4];
+        }
+        let [x0, y0, x1, y1] = self.last_bbox;
+        let (bw, bh) = ((x1 - x0) as f32, (y1 - y0) as f32);
+        vec![self.last_area as f32, bw, bh, self.last_area as f32 / (bw * bh)]
+    }
+
+    /// Re-run the opening of the last frame at `radius` (used for both the
+    /// erosion and the dilation) for a kernel-size slider, without touching
+    /// history or any other state. Works from the last refine's cached
+    /// thresholded average and input: the eroded pieces overlapping the last
+    /// selection are kept and regrown, then the soft values re-applied. Fade-out
+    /// and result blending are not applied. Returns an empty vec before the
+    /// first frame.
+    pub fn preview_kernel(&self, radius: i32) -> Vec<f32> {
+        let size = self.width * self.height;
+        if self.last_binary.len() != size || self.last_final.len() != size {
+            return Vec::new();
+        }
+        let radius = radius.max(0);
+        let eroded = self.erode(&self.last_binary, radius);
+        let marker: Vec<u8> = eroded.iter().zip(&self.last_final).map(|(&e, &f)| e & (f > 0.0) as u8).collect();
+        let isolated = self.reconstruct(&marker, &eroded);
+        let dilated = self.dilate(&isolated, radius);
+        let dilated = if self.config.edge_smooth && !self.config.hard_output { self.majority_smooth(&dilated, 2) } else { dilated };
+        self.reapply(&self.last_input, &dilated, &isolated)
+    }
+
+    /// Pixel `[x, y]` the flood fill of the last refine actually started from,
+    /// which differs from the click when it landed on background and snapped
+    /// to the nearest foreground within 20 px, e.g. for drawing a snap dot.
+    /// With several clicks this is the last one. Empty if no seed was found or
+    /// the frame was refined without a click.
+    pub fn last_seed(&self) -> Vec<u32> {
+        self.last_seed.map_or(Vec::new(), |(x, y)| vec![x as u32, y as u32])
+    }
+
+    /// Debug counter of structuring elements built so far. Elements are cached
+    /// per radius, so this stays flat in steady-state playback and only rises
+    /// when a new radius is used or the element shape, length, rotation or
+    /// pixel aspect changes.
+    pub fn kernel_builds(&self) -> u32 {
+        self.kernel_builds.get()
+    }
+
+    /// Debug counter of gamma tables built for the edge blur of
+    /// `antialias_edge`. The table is built on the first gamma-space blur and
+    /// reused by every frame after it until the feather space is switched to
+    /// linear, so this stays at 1 in steady state.
+    pub fn feather_lut_builds(&self) -> u32 {
+        self.feather_lut_builds.get()
+    }
+
+    /// Guess whether the last selection is really two subjects joined by a
+    /// bridge the erosion failed to break. The selection's distance transform
+    /// is thresholded at 60% of its peak; more than one separate core region
+    /// means there are several thick parts joined only through something
+    /// narrower than 60% of the thickest width. Lumpy single subjects (a head
+    /// on narrow shoulders, two fists) can trigger it too, so treat `true` as
+    /// a prompt to offer a split. False for empty selections and ones less
+    /// than about four pixels thick.
+    pub fn detect_merge(&self) -> bool {
+        if self.last_area == 0 || self.last_final.len() != self.width * self.height {
+            return false;
+        }
+        let binary: Vec<u8> = self.last_final.iter().map(|&v| (v > 0.0) as u8).collect();
+        let dist = self.distance_transform(&binary, true);
+        let peak = dist.iter().cloned().fold(0.0, f32::max);
+        if peak < 2.0 {
+            return false;
+        }
+        let cores: Vec<u8> = dist.iter().map(|&d| (d >= 0.6 * peak) as u8).collect();
+        self.label_components(&cores, 0).1 > 1
+    }
+
+    /// Heuristic 0-1 quality score of the last selection, for tuning kernel and
+    /// threshold settings automatically; it is not a comparison with ground
+    /// truth. Weighted sum of:
+    /// - 0.3 × solidity: area over convex-hull area;
+    /// - 0.3 × smoothness: convex-hull perimeter over `last_perimeter`, capped
+    ///   at 1, so ragged boundaries score low;
+    /// - 0.3 × stability: `1 - 4 × mean temporal variance` over the selected
+    ///   pixels (1 with fewer than two frames of history);
+    /// - 0.1 × `1 / (1 + holes)`, counting enclosed background regions.
+    ///
+    /// 0 for an empty selection.
+    pub fn last_quality(&self) -> f32 {
+        let size = self.width * self.height;
+        if self.last_area == 0 || self.last_final.len() != size {
+            return 0.0;
+        }
+
+        let hull = self.selection_hull();
+        let (mut hull_area, mut hull_perimeter) = (0.0, 0.0);
+        for k in 0..hull.len() {
+            let (ax, ay) = hull[k];
+            let (bx, by) = hull[(k + 1) % hull.len()];
+            hull_area += ax * by - bx * ay;
+            hull_perimeter += ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt();
+        }
+        let hull_area = hull_area.abs() / 2.0;
+        let solidity = if hull_area > 0.0 { (self.last_area as f32 / hull_area).min(1.0) } else { 1.0 };
+        let perimeter = self.last_perimeter();
+        let smoothness = if perimeter > 0.0 { (hull_perimeter / perimeter).min(1.0) } else { 1.0 };
+
+        let variance = self.temporal_variance();
+        let stability = if variance.is_empty() {
+            1.0
+        } else {
+            let selected = self.last_final.iter().zip(&variance).filter(|(&f, _)| f > 0.0);
+            let sum: f32 = selected.map(|(_, &v)| v).sum();
+            (1.0 - 4.0 * sum / self.last_area as f32).clamp(0.0, 1.0)
+        };
+
+        let foreground: Vec<u8> = self.last_final.iter().map(|&v| (v > 0.0) as u8).collect();
+        let holes = self.hole_areas(&foreground).1.iter().flatten().count();
+
+        0.3 * solidity + 0.3 * smoothness + 0.3 * stability + 0.1 / (1.0 + holes as f32)
+    }
+
+    /// Guess whether the last click grabbed the background instead of a
+    /// subject: true when the selection covers more than 40% of the frame and
+    /// the model's mean confidence over it (the last input, before smoothing)
+    /// is below 0.7. A real subject that large is usually a close-up the model
+    /// is sure about, while a background fill is a big region just above the
+    /// threshold. Advisory only, e.g. for a "you may have selected the
+    /// background" hint; nothing in the pipeline acts on it. False for an
+    /// empty selection.
+    pub fn likely_background(&self) -> bool {
+        let size = self.width * self.height;
+        if self.last_area == 0 || self.last_final.len() != size || self.last_input.len() != size {
+            return false;
+        }
+        let sum: f32 = self.last_final.iter().zip(&self.last_input).filter(|(&f, _)| f > 0.0).map(|(_, &v)| v).sum();
+        let confidence = sum / self.last_area as f32;
+        self.last_area as f32 > BACKGROUND_AREA_FRACTION * size as f32 && confidence < BACKGROUND_CONFIDENCE
+    }
+
+    /// Movement of the selection's centroid between the previous and the last
+    /// refined frame as `[vx, vy]`, in fractions of the frame width and height
+    /// per frame. `[0, 0]` on the first frame, after a `reset`, and whenever
+    /// either frame's selection is empty.
+    pub fn last_velocity(&self) -> Vec<f32> {
+        self.velocity.to_vec()
+    }
+
+    /// Heading of the selection over the history window: the angle in radians
+    /// of the centroid's average displacement per frame across the last
+    /// `max_history` frames (in pixels, `atan2(dy, dx)` with y pointing down,
+    /// so 0 is rightward and π/2 downward). Steadier than `last_velocity` for
+    /// motion-triggered effects. NaN when the average movement is below 0.5
+    /// pixels per frame, where the direction is undefined, and until two
+    /// consecutive frames had a selection (an empty frame or `reset` starts
+    /// over).
+    pub fn motion_direction(&self) -> f32 {
+        let track = &self.centroid_track;
+        if track.len() < 2 {
+            return f32::NAN;
+        }
+        let ((x0, y0), (x1, y1)) = (track[0], track[track.len() - 1]);
+        let steps = (track.len() - 1) as f32;
+        let dx = (x1 - x0) * self.width as f32 / steps;
+        let dy = (y1 - y0) * self.height as f32 / steps;
+        if dx.hypot(dy) < MIN_MOTION_PX {
+            return f32::NAN;
+        }
+        dy.atan2(dx)
+    }
+
+    /// Centre of mass of the last result weighted by its soft values, as
+    /// `[cx, cy]` in the same normalized 0-1 coordinates as clicks (pixel
+    /// centres). Edge pixels fading in and out move it by their weight rather
+    /// than a whole pixel, so it jitters less than the binary centroid behind
+    /// `last_velocity`, for steadier auto-pan. Empty for an empty selection.
+    pub fn last_weighted_centroid(&self) -> Vec<f32> {
+        let w = self.width;
+        let (mut sum, mut sx, mut sy) = (0.0f64, 0.0f64, 0.0f64);
+        for (i, &v) in self.last_final.iter().enumerate().filter(|(_, &v)| v > 0.0) {
+            let v = v.min(1.0) as f64;
+            sum += v;
+            sx += v * ((i % w) as f64 + 0.5);
+            sy += v * ((i / w) as f64 + 0.5);
+        }
+        if sum <= 0.0 {
+            return Vec::new();
+        }
+        vec![(sx / sum) as f32 / self.width as f32, (sy / sum) as f32 / self.height as f32]
+    }
+
+    /// How much the selection grew between the previous and the last refined
+    /// frame: the ratio of their bounding-box diagonals, so above 1 means the
+    /// subject got bigger (approaching, or zooming in) and below 1 smaller,
+    /// for follow-cam auto zoom. 1.0 on the first frame, after a `reset`, and
+    /// whenever either frame's selection is empty.
+    pub fn last_scale_delta(&self) -> f32 {
+        self.scale_delta
+    }
+
+    /// Number of pixels whose selection changed between the previous and the
+    /// last refined frame (the XOR of the two final masks, a pixel counting as
+    /// selected above 0), for thresholding "significant change" events in
+    /// absolute terms. 0 on the first frame and after a `reset`.
+    pub fn last_churn_area(&self) -> u32 {
+        self.churn_area
+    }
+
+    /// Whether the selection has settled: true once each of the last 5 frames
+    /// changed by less than `tolerance`, measured as `last_churn_area` over
+    /// the larger of the two frames' selection areas (so 0.01 allows 1% of the
+    /// selection to toggle per frame). False until 5 frames have been compared
+    /// since the first frame or the last `reset`. For waiting on a stable
+    /// selection before grabbing a keyframe.
+    pub fn is_stable(&self, tolerance: f32) -> bool {
+        self.flicker.len() == STABILITY_WINDOW && self.flicker.iter().all(|&f| f < tolerance)
+    }
+
+    /// Mean model confidence (the last input, before smoothing) over the soft
+    /// edge band of the last result, the pixels whose final value is strictly
+    /// between 0 and 1. Low values mean the model is unsure at the boundary,
+    /// a cue to raise smoothing or feathering. 0 for an empty selection or a
+    /// result without a soft edge (e.g. `set_hard_output`).
+    pub fn last_edge_confidence(&self) -> f32 {
+        self.edge_confidence
+    }
+
+    /// Length of the last selection's boundary, in pixels: boundary pixels (a
+    /// foreground pixel with a background 4-neighbour or on the frame edge)
+    /// are chained to their neighbouring boundary pixels, straight steps
+    /// counting 1 and diagonal steps √2. Compare with the area from
+    /// `last_shape_stats` for a compactness score (`perimeter² / area`).
+    /// 0 for an empty selection.
+    pub fn last_perimeter(&self) -> f32 {
+        let (w, h) = (self.width as i32, self.height as i32);
+        if self.last_area == 0 || self.last_final.len() != (w * h) as usize {
+            return 0.0;
+        }
+        let fg = |x: i32, y: i32| x >= 0 && y >= 0 && x < w && y < h && self.last_final[(y * w + x) as usize] > 0.0;
+        let edge = |x: i32, y: i32| fg(x, y) && !(fg(x - 1, y) && fg(x + 1, y) && fg(x, y - 1) && fg(x, y + 1));
+
+        // Each link is seen from both ends, so only look right and down.
+        let mut length = 0.0;
+        for y in 0..h {
+            for x in 0..w {
+                if !edge(x, y) {
+                    continue;
+                }
+                length += edge(x + 1, y) as u32 as f32 + edge(x, y + 1) as u32 as f32;
+                for dx in [-1, 1] {
+                    if edge(x + dx, y + 1) && !edge(x + dx, y) && !edge(x, y + 1) {
+                        length += std::f32::consts::SQRT_2;
+                    }
+                }
+            }
+        }
+        length
+    }
+
+    /// Signed distance field of the selection boundary in `mask`: positive
+    /// inside the foreground, negative outside, in pixels from each pixel
+    /// centre to the boundary, so pixels on either side of an edge read +0.5
+    /// and -0.5 (exact Euclidean distances). The frame edge is not a boundary.
+    /// With no foreground or no background at all, the missing side is
+    /// clamped to the frame diagonal. For shader-based scaling and outlines.
+    /// Returns an empty vec if the length is not `width * height`.
+    pub fn signed_distance_field(&self, mask: &[u8]) -> Vec<f32> {
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let inverse: Vec<u8> = mask.iter().map(|&v| (v == 0) as u8).collect();
+        let inside = self.distance_transform(mask, false);
+        let outside = self.distance_transform(&inverse, false);
+        let diagonal = (self.width as f32).hypot(self.height as f32);
+        mask.iter()
+            .enumerate()
+            .map(|(i, &v)| if v != 0 { (inside[i] - 0.5).min(diagonal) } else { -(outside[i] - 0.5).min(diagonal) })
+            .collect()
+    }
+
+    /// The convex deficiency of `mask`: pixels inside the convex hull of its
+    /// foreground but not foreground themselves (0/1), i.e. the bays and
+    /// notches such as the gap between an arm and the torso. A pixel is inside
+    /// the hull when its centre is. The hull spans all components together.
+    /// Returns an empty vec if the length is not `width * height`.
+    pub fn concavity_regions(&self, mask: &[u8]) -> Vec<u8> {
+        let w = self.width;
+        if mask.len() != w * self.height {
+            return Vec::new();
+        }
+        let hull = self.pixel_hull(mask);
+        let mut out = vec![0u8; mask.len()];
+        if hull.len() < 3 {
+            return out;
+        }
+        let inside = |px: f32, py: f32| {
+            (0..hull.len()).all(|k| {
+                let (ax, ay) = hull[k];
+                let (bx, by) = hull[(k + 1) % hull.len()];
+                (bx - ax) * (py - ay) - (by - ay) * (px - ax) >= 0.0
+            })
+        };
+        for (i, o) in out.iter_mut().enumerate() {
+            if mask[i] == 0 && inside((i % w) as f32 + 0.5, (i / w) as f32 + 0.5) {
+                *o = 1;
+            }
+        }
+        out
+    }
+
+    /// Solidity of the last selection: its area divided by the area of its
+    /// convex hull (the pixels whose centres lie inside the hull spanning all
+    /// components, as in `concavity_regions`). Near 1 for a clean convex blob;
+    /// a standing person is typically 0.6-0.85, and values below about 0.5
+    /// usually mean a ragged edge, a spurious second component or a selection
+    /// worth flagging for review. 1.0 when the selection is empty or a single
+    /// pixel.
+    pub fn last_solidity(&self) -> f32 {
+        if self.last_final.len() != self.width * self.height {
+            return 1.0;
+        }
+        let binary: Vec<u8> = self.last_final.iter().map(|&v| (v > 0.0) as u8).collect();
+        let area = binary.iter().filter(|&&v| v != 0).count();
+        if area <= 1 {
+            return 1.0;
+        }
+        let deficiency = self.concavity_regions(&binary).iter().filter(|&&v| v != 0).count();
+        area as f32 / (area + deficiency) as f32
+    }
+
+    /// Image moments of the last selection's binary footprint as
+    /// `[m00, m10, m01, mu20, mu11, mu02]`: the raw moments (area and first
+    /// moments, with pixel `(x, y)` at its index) followed by the second-order
+    /// central moments about the centroid. Orientation is
+    /// `0.5 * atan2(2 mu11, mu20 - mu02)` and eccentricity follows from the
+    /// eigenvalues of `[[mu20, mu11], [mu11, mu02]]`. All zeros when the
+    /// selection is empty.
+    pub fn image_moments(&self) -> Vec<f32> {
+        if self.last_area == 0 || self.last_final.len() != self.width * self.height {
+            return vec![0.0; 6];
+        }
+        let w = self.width;
+        let (mut m00, mut m10, mut m01, mut m20, mut m11, mut m02) = (0.0f64, 0.0f64, 0.0f64, 0.0f64, 0.0f64, 0.0f64);
+        for (i, _) in self.last_final.iter().enumerate().filter(|(_, &v)| v > 0.0) {
+            let (x, y) = ((i % w) as f64, (i / w) as f64);
+            m00 += 1.0;
+            m10 += x;
+            m01 += y;
+            m20 += x * x;
+            m11 += x * y;
+            m02 += y * y;
+        }
+        let (cx, cy) = (m10 / m00, m01 / m00);
+        vec![
+            m00 as f32,
+            m10 as f32,
+            m01 as f32,
+            (m20 - cx * m10) as f32,
+            (m11 - cx * m01) as f32,
+            (m02 - cy * m01) as f32,
+        ]
+    }
+
+    /// Orientation of the last selection as `[angle, eccentricity]`, from its
+    /// second-order central moments (`image_moments`). `angle` is the major
+    /// axis in radians in `(-π/2, π/2]`, from the x axis with y pointing down,
+    /// so positive angles turn clockwise on screen. `eccentricity` is that of
+    /// the ellipse with the same moments: 0 for a circle and approaching 1 for
+    /// a line. `[0, 0]` for an empty or circular selection.
+    pub fn last_orientation(&self) -> Vec<f32> {
+        let m = self.image_moments();
+        let (mu20, mu11, mu02) = (m[3], m[4], m[5]);
+        let spread = ((mu20 - mu02).powi(2) + 4.0 * mu11 * mu11).sqrt();
+        let major = (mu20 + mu02 + spread) / 2.0;
+        let minor = (mu20 + mu02 - spread) / 2.0;
+        if m[0] == 0.0 || major <= 0.0 || spread <= major * 1e-6 {
+            return vec![0.0, 0.0];
+        }
+        let angle = 0.5 * (2.0 * mu11).atan2(mu20 - mu02);
+        vec![angle, (1.0 - minor.max(0.0) / major).sqrt()]
+    }
+
+    /// Smallest circle enclosing the last selection as `[cx, cy, radius]` in
+    /// pixels, for radial badges and menus around the subject. Computed with
+    /// Welzl's incremental algorithm over the convex hull of the selection's
+    /// outline (pixel corners, as in `oriented_bbox`), so a single pixel gets
+    /// radius √2/2. Empty when the selection is empty.
+    pub fn bounding_circle(&self) -> Vec<f32> {
+        if self.last_area == 0 || self.last_final.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let pts = self.selection_hull();
+        let (mut c, mut r2) = ((0.0f32, 0.0f32), -1.0f32);
+        let outside = |c: (f32, f32), r2: f32, p: (f32, f32)| (p.0 - c.0).powi(2) + (p.1 - c.1).powi(2) > r2 * (1.0 + 1e-5) + 1e-6;
+        let span = |a: (f32, f32), b: (f32, f32)| {
+            let c = ((a.0 + b.0) / 2.0, (a.1 + b.1) / 2.0);
+            (c, (a.0 - c.0).powi(2) + (a.1 - c.1).powi(2))
+        };
+        for i in 0..pts.len() {
+            if !outside(c, r2, pts[i]) {
+                continue;
+            }
+            (c, r2) = (pts[i], 0.0);
+            for j in 0..i {
+                if !outside(c, r2, pts[j]) {
+                    continue;
+                }
+                (c, r2) = span(pts[i], pts[j]);
+                for k in 0..j {
+                    if outside(c, r2, pts[k]) {
+                        (c, r2) = Self::circumcircle(pts[i], pts[j], pts[k]);
+                    }
+                }
+            }
+        }
+        vec![c.0, c.1, r2.max(0.0).sqrt()]
+    }
+
+    /// Centre and squared radius of the circle through `a`, `b` and `c`; for
+    /// (nearly) collinear points, the circle spanning the farthest pair.
+    fn circumcircle(a: (f32, f32), b: (f32, f32), c: (f32, f32)) -> ((f32, f32), f32) {
+        let (bx, by) = (b.0 - a.0, b.1 - a.1);
+        let (cx, cy) = (c.0 - a.0, c.1 - a.1);
+        let d = 2.0 * (bx * cy - by * cx);
+        if d.abs() < 1e-9 {
+            let pairs = [(a, b), (a, c), (b, c)];
+            let dist = |(p, q): ((f32, f32), (f32, f32))| (p.0 - q.0).powi(2) + (p.1 - q.1).powi(2);
+            let (p, q) = pairs.into_iter().max_by(|&x, &y| dist(x).total_cmp(&dist(y))).unwrap_or((a, b));
+            let m = ((p.0 + q.0) / 2.0, (p.1 + q.1) / 2.0);
+            return (m, dist((p, q)) / 4.0);
+        }
+        let (b2, c2) = (bx * bx + by * by, cx * cx + cy * cy);
+        let ux = (cy * b2 - by * c2) / d;
+        let uy = (bx * c2 - cx * b2) / d;
+        ((a.0 + ux, a.1 + uy), ux * ux + uy * uy)
+    }
+
+    /// Minimum-area rotated rectangle around the last selection as
+    /// `[cx, cy, w, h, angle]`, found with rotating calipers over the convex
+    /// hull of its outline (pixel corners). `angle` is in radians in `[0, π/2)`,
+    /// measured from the x axis (y down) to the `w` side. Selections of fewer
+    /// than three pixels, or with a degenerate hull, fall back to the
+    /// axis-aligned box with angle 0. All zeros when the selection is empty.
+    pub fn oriented_bbox(&self) -> Vec<f32> {
+        if self.last_area == 0 || self.last_final.len() != self.width * self.height {
+            return vec![0.0; 5];
+        }
+        let [x0, y0, x1, y1] = self.last_bbox;
+        let axis = vec![
+            (x0 + x1) as f32 / 2.0,
+            (y0 + y1) as f32 / 2.0,
+            (x1 - x0) as f32,
+            (y1 - y0) as f32,
+            0.0,
+        ];
+        if self.last_area < 3 {
+            return axis;
+        }
+
+        let hull = self.selection_hull();
+        if hull.len() < 3 {
+            return axis;
+        }
+
+        let mut best: Option<(f32, Vec<f32>)> = None;
+        for k in 0..hull.len() {
+            let (ax, ay) = hull[k];
+            let (bx, by) = hull[(k + 1) % hull.len()];
+            let len = ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt();
+            if len == 0.0 {
+                continue;
+            }
+            let (ux, uy) = ((bx - ax) / len, (by - ay) / len);
+            let (mut lo_u, mut hi_u, mut lo_v, mut hi_v) = (f32::MAX, f32::MIN, f32::MAX, f32::MIN);
+            for &(px, py) in &hull {
+                let pu = px * ux + py * uy;
+                let pv = -px * uy + py * ux;
+                lo_u = lo_u.min(pu);
+                hi_u = hi_u.max(pu);
+                lo_v = lo_v.min(pv);
+                hi_v = hi_v.max(pv);
+            }
+            let area = (hi_u - lo_u) * (hi_v - lo_v);
+            if best.as_ref().is_none_or(|(a, _)| area < *a) {
+                let (mu, mv) = ((lo_u + hi_u) / 2.0, (lo_v + hi_v) / 2.0);
+                let (cx, cy) = (mu * ux - mv * uy, mu * uy + mv * ux);
+                let (mut bw, mut bh) = (hi_u - lo_u, hi_v - lo_v);
+                let mut angle = uy.atan2(ux).rem_euclid(std::f32::consts::PI);
+                if angle >= std::f32::consts::FRAC_PI_2 {
+                    angle -= std::f32::consts::FRAC_PI_2;
+                    std::mem::swap(&mut bw, &mut bh);
+                }
+                best = Some((area, vec![cx, cy, bw, bh, angle]));
+            }
+        }
+        best.map_or(axis, |(_, b)| b)
+    }
+
+    /// Projection profiles of the last selection: the number of foreground
+    /// pixels in each row followed by each column (`height + width` values),
+    /// for finding the subject's extent, symmetry and natural cut lines.
+    /// Empty before the first frame.
+    pub fn projection_profiles(&self) -> Vec<u32> {
+        self.last_profiles.clone()
+    }
+
+    /// The last selection on a coarse `cols x rows` grid, row-major: each cell
+    /// holds the fraction of its area covered by selected pixels (final value
+    /// above 0), for layout and collision checks such as "the subject is in
+    /// the left third". Cells split the frame evenly even when the size isn't
+    /// divisible, so a pixel straddling two cells counts toward both by the
+    /// area it has in each. Empty if `cols` or `rows` is 0 or before the first
+    /// frame.
+    pub fn occupancy_grid(&self, cols: usize, rows: usize) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        if cols == 0 || rows == 0 || w == 0 || h == 0 || self.last_final.len() != w * h {
+            return Vec::new();
+        }
+        // Overlap of pixel `p` with each cell along an axis of `n` pixels cut into `cells`
+        let spans = |n: usize, cells: usize| -> Vec<Vec<(usize, f32)>> {
+            let step = n as f32 / cells as f32;
+            (0..n)
+                .map(|p| {
+                    let (p0, p1) = (p as f32, p as f32 + 1.0);
+                    let first = ((p0 / step) as usize).min(cells - 1);
+                    (first..cells)
+                        .map(|c| (c, (p1.min((c + 1) as f32 * step) - p0.max(c as f32 * step)).max(0.0)))
+                        .take_while(|&(c, a)| a > 0.0 || c == first)
+                        .collect()
+                })
+                .collect()
+        };
+        let (xs, ys) = (spans(w, cols), spans(h, rows));
+        let mut grid = vec![0.0f32; cols * rows];
+        for (i, _) in self.last_final.iter().enumerate().filter(|(_, &v)| v > 0.0) {
+            for &(cy, ay) in &ys[i / w] {
+                for &(cx, ax) in &xs[i % w] {
+                    grid[cy * cols + cx] += ax * ay;
+                }
+            }
+        }
+        let cell_area = (w as f32 / cols as f32) * (h as f32 / rows as f32);
+        grid.iter().map(|&a| (a / cell_area).min(1.0)).collect()
+    }
+
+    /// Bounding box of the last selection as `[x, y, width, height]` in pixels,
+    /// for placing the buffer from `cropped_mask`. Empty when the selection is
+    /// empty. With `set_bbox_smoothing` on it is the smoothed box instead,
+    /// rounded to whole pixels, which no longer matches `cropped_mask` exactly.
+    pub fn last_bounding_box(&self) -> Vec<u32> {
+        if self.last_area == 0 {
+            return Vec::new();
+        }
+        let [x0, y0, x1, y1] = match self.smoothed_bbox {
+            Some(b) if self.config.bbox_smoothing > 0.0 => b.map(|v| v.round() as usize),
+            _ => self.last_bbox,
+        };
+        vec![x0 as u32, y0 as u32, x1.saturating_sub(x0) as u32, y1.saturating_sub(y0) as u32]
+    }
+
+    /// Smooth the box `last_bounding_box` reports across frames so overlays
+    /// that follow it stop shaking as edge pixels toggle: each corner becomes
+    /// `alpha * previous + (1 - alpha) * raw`. 0 (default) reports the raw box;
+    /// higher values are calmer but lag further behind, clamped to 0-0.99. The
+    /// smoothing restarts from the raw box after an empty frame or a `reset`.
+    pub fn set_bbox_smoothing(&mut self, alpha: f32) {
+        self.config.bbox_smoothing = if alpha.is_finite() { alpha.clamp(0.0, 0.99) } else { 0.0 };
+    }
+
+    /// Fraction of the last selection's foreground pixels whose centres fall
+    /// inside the rectangle at `(x, y)` of size `w` x `h`, all normalized 0-1
+    /// like clicks, e.g. to check a subject against a safe area or a
+    /// rule-of-thirds zone. 0.0 for an empty selection.
+    pub fn overlap_with_rect(&self, x: f32, y: f32, w: f32, h: f32) -> f32 {
+        if self.last_area == 0 || self.last_final.len() != self.width * self.height {
+            return 0.0;
+        }
+        let (fw, fh) = (self.width as f32, self.height as f32);
+        let inside = self
+            .last_final
+            .iter()
+            .enumerate()
+            .filter(|&(i, &v)| {
+                let px = ((i % self.width) as f32 + 0.5) / fw;
+                let py = ((i / self.width) as f32 + 0.5) / fh;
+                v > 0.0 && px >= x && px < x + w && py >= y && py < y + h
+            })
+            .count();
+        inside as f32 / self.last_area as f32
+    }
+
+    /// The most recent result again, e.g. for a redraw, without re-running
+    /// the pipeline or pushing a duplicate frame into the history. Same layout
+    /// as the refine calls return (see `set_output_layout`). Empty before the
+    /// first frame and after `reset`.
+    pub fn last_mask(&self) -> Vec<f32> {
+        if self.last_final.is_empty() {
+            return Vec::new();
+        }
+        self.to_output_layout(self.last_final.clone(), self.width, self.height)
+    }
+
+    /// The last final mask cropped to `last_bounding_box`, row-major and
+    /// `width * height` of that box, so a small subject in a large frame costs
+    /// little to transfer. Always row-major, whatever the output layout.
+    /// Empty when the selection is empty.
+    pub fn cropped_mask(&self) -> Vec<f32> {
+        if self.last_area == 0 || self.last_final.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let [x0, y0, x1, y1] = self.last_bbox;
+        let w = self.width;
+        (y0..y1).flat_map(|y| self.last_final[y * w + x0..y * w + x1].iter().copied()).collect()
+    }
+
+    /// The last final mask shrunk by an integer `scale` with area averaging, for
+    /// thumbnails. The output is `ceil(width / scale)` by `ceil(height / scale)`,
+    /// row-major; cells in a partial last row or column average only the
+    /// pixels they cover. Returns an empty vec for `scale == 0` or before the
+    /// first frame.
+    pub fn preview_mask(&self, scale: usize) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        if scale == 0 || self.last_final.len() != w * h {
+            return Vec::new();
+        }
+
+        let pw = w.div_ceil(scale);
+        let ph = h.div_ceil(scale);
+        let mut sums = vec![0.0f32; pw * ph];
+        let mut counts = vec![0u32; pw * ph];
+        for (i, &v) in self.last_final.iter().enumerate() {
+            let cell = (i / w / scale) * pw + (i % w) / scale;
+            sums[cell] += v;
+            counts[cell] += 1;
+        }
+        sums.iter().zip(&counts).map(|(&s, &n)| s / n as f32).collect()
+    }
+
+    /// Retain the temporal average of each frame for `last_averaged`. Off by
+    /// default so the extra frame-sized buffer is not kept around; turning it
+    /// off frees it.
+    pub fn set_keep_averaged(&mut self, on: bool) {
+        self.config.keep_averaged = on;
+        if !on {
+            self.last_averaged = Vec::new();
+        }
+    }
+
+    /// The soft temporal average of the last refined frame, before auto gain,
+    /// thresholding and morphology, e.g. for tuning the threshold
+    /// interactively. Empty unless `set_keep_averaged` was on for that frame.
+    pub fn last_averaged(&self) -> Vec<f32> {
+        self.last_averaged.clone()
+    }
+
+    /// Retain the isolated binary of each frame for `last_isolated`. Off by
+    /// default; turning it off frees the buffer.
+    pub fn set_keep_isolated(&mut self, on: bool) {
+        self.config.keep_isolated = on;
+        if !on {
+            self.last_isolated = Vec::new();
+        }
+    }
+
+    /// Debug mode: retain a full trace of each refined frame for
+    /// `export_layers`. Off by default; turning it off frees the buffer.
+    pub fn set_debug_layers(&mut self, on: bool) {
+        self.config.debug_layers = on;
+        if !on {
+            self.last_layers = Vec::new();
+        }
+    }
+
+    /// The last frame's pipeline stages as 5 consecutive row-major
+    /// `width * height` planes:
+    /// 0 = temporal average the thresholding started from,
+    /// 1 = thresholded binary (0/1),
+    /// 2 = isolated binary before dilation (0/1, as in `last_isolated`),
+    /// 3 = dilated mask with the soft values re-applied, before hole filling,
+    ///     paint, blending and fade-out,
+    /// 4 = final mask (as returned, before the output layout and background
+    ///     floor).
+    /// The single-call version of `last_averaged`, `last_isolated` and
+    /// `last_mask`, for saving a complete trace of one frame. Empty unless
+    /// `set_debug_layers` was on for that frame.
+    pub fn export_layers(&self) -> Vec<f32> {
+        self.last_layers.clone()
+    }
+
+    /// The tight "core" selection of the last refined frame: the component(s)
+    /// the flood fill kept (0/1), before dilation, edge smoothing and the soft
+    /// re-application that produce the returned mask. Row-major. Empty unless
+    /// `set_keep_isolated` was on for that frame.
+    pub fn last_isolated(&self) -> Vec<u8> {
+        self.last_isolated.clone()
+    }
+
+    /// Experimental: the characteristic size of the features in the last
+    /// frame's mask, in pixels, from its autocorrelation, as a data-driven
+    /// starting point for the morphology radii alongside `suggest_kernel`.
+    /// The mean-subtracted mask is correlated with itself shifted by `r`
+    /// pixels, averaged over the horizontal, vertical and both diagonal
+    /// directions, and the result is the lag where that correlation first
+    /// falls below half its value at 0 (interpolated), roughly half the
+    /// width of a typical blob or stripe. Searches lags up to a quarter of the
+    /// shorter frame side and returns that limit if the correlation stays
+    /// high. Computed from the last averaged mask, which is only kept while
+    /// `set_keep_averaged` is on; otherwise it falls back to the last raw
+    /// input, which is noisier. 0 for an empty frame or a constant mask.
+    pub fn dominant_scale(&self) -> f32 {
+        let (w, h) = (self.width, self.height);
+        let source = if self.last_averaged.is_empty() { &self.last_input } else { &self.last_averaged };
+        if source.len() != w * h || w < 4 || h < 4 {
+            return 0.0;
+        }
+        let mean = source.iter().sum::<f32>() / source.len() as f32;
+        let centred: Vec<f32> = source.iter().map(|&v| v - mean).collect();
+        let correlation = |dx: i32, dy: i32| -> f32 {
+            let (mut sum, mut n) = (0.0f32, 0);
+            for y in 0..h as i32 - dy.abs() {
+                let y0 = if dy < 0 { y - dy } else { y };
+                for x in 0..w as i32 - dx.abs() {
+                    let x0 = if dx < 0 { x - dx } else { x };
+                    let a = centred[(y0 * w as i32 + x0) as usize];
+                    let b = centred[((y0 + dy) * w as i32 + x0 + dx) as usize];
+                    sum += a * b;
+                    n += 1;
+                }
+            }
+            if n > 0 { sum / n as f32 } else { 0.0 }
+        };
+        let zero = correlation(0, 0);
+        if zero <= 1e-8 {
+            return 0.0;
+        }
+        let radial = |r: i32| -> f32 {
+            let d = ((r as f32) / std::f32::consts::SQRT_2).round() as i32;
+            (correlation(r, 0) + correlation(0, r) + correlation(d, d) + correlation(d, -d)) / (4.0 * zero)
+        };
+        let max_lag = (w.min(h) / 4) as i32;
+        let mut prev = 1.0;
+        for r in 1..=max_lag {
+            let c = radial(r);
+            if c < 0.5 {
+                return (r - 1) as f32 + (prev - 0.5) / (prev - c);
+            }
+            prev = c;
+        }
+        max_lag as f32
+    }
+
+    /// Distribution of the last frame's mask values over 0-1 in `bins` equal
+    /// buckets (values outside are clamped into the end buckets), for a
+    /// histogram display when picking a threshold. Uses the temporal average
+    /// when `set_keep_averaged` kept it, otherwise the last input mask. Returns
+    /// an empty vec if `bins` is 0.
+    pub fn histogram(&self, bins: usize) -> Vec<u32> {
+        if bins == 0 {
+            return Vec::new();
+        }
+        let source = if self.last_averaged.is_empty() { &self.last_input } else { &self.last_averaged };
+        let mut counts = vec![0u32; bins];
+        for &v in source {
+            let bin = (v.clamp(0.0, 1.0) * bins as f32) as usize;
+            counts[bin.min(bins - 1)] += 1;
+        }
+        counts
+    }
+
+    /// Per-pixel variance of the frames currently in the history window, for
+    /// spotting regions where the mask flickers. Returns an empty vec when
+    /// fewer than two frames are stored.
+    pub fn temporal_variance(&self) -> Vec<f32> {
+        if self.history.len() < 2 {
+            return Vec::new();
+        }
+        let n = self.history.len() as f32;
+        let frames: Vec<Cow<[f32]>> = self.history.iter().map(Frame::decode).collect();
+        (0..self.width * self.height)
+            .map(|i| {
+                let mean = frames.iter().map(|f| f[i]).sum::<f32>() / n;
+                frames.iter().map(|f| (f[i] - mean) * (f[i] - mean)).sum::<f32>() / n
+            })
+            .collect()
+    }
+
+    /// Row-major indices of the pixels where the last final mask exceeds 0.001.
+    /// Much smaller than the dense mask for sparse selections, but larger once
+    /// more than about a quarter of the frame is selected (4 bytes per index).
+    pub fn selected_indices(&self) -> Vec<u32> {
+        self.last_final
+            .iter()
+            .enumerate()
+            .filter(|(_, &v)| v > 1e-3)
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Process a new mask frame, seeding the selection from the click at the
+    /// normalized `(click_x, click_y)`. Delegates to the full pipeline in
+    /// `refine_frame`: input filters, temporal smoothing, threshold, erosion,
+    /// isolation of the clicked component, dilation and soft re-application,
+    /// then the output stages such as blending and fade-out. Every setting
+    /// applies; the core ones are `set_threshold`, the erode and dilate radii,
+    /// the structuring element and `set_border_mode`.
+    pub fn refine_mask(&mut self, input_mask: &[f32], click_x: f32, click_y: f32) -> Vec<f32> {
+        let seed = self.click_to_pixel(click_x, click_y);
+        self.refine_seeded(input_mask, seed.as_slice(), 1.0)
+    }
+
+    /// Start a chunked `refine_mask` for frames too large to refine within one
+    /// animation frame: call `refine_mask_step` until it returns true, then
+    /// `refine_mask_finish` for the result, the same one `refine_mask` gives.
+    /// The frame enters the history here. The chunked path runs the core
+    /// pipeline only: the input filters, the weighted temporal mean,
+    /// threshold, erosion, flood fill from the click, dilation, soft
+    /// re-application (alpha curve and gamma, hard or binary output,
+    /// confidence floor) and the paint layer, with any structuring element,
+    /// border mode, barrier, history precision and output layout. Every other
+    /// setting must be at its default. Don't run other refine calls until the
+    /// chunked one is finished; a new `refine_mask_begin` abandons it. Returns
+    /// false (and starts nothing) if the length is not `width * height` or a
+    /// setting outside the core pipeline is in use.
+    pub fn refine_mask_begin(&mut self, input_mask: &[f32], click_x: f32, click_y: f32) -> bool {
+        let size = self.width * self.height;
+        self.status = 0;
+        self.chunked = None;
+        if input_mask.len() != size || !self.chunkable() {
+            return false;
+        }
+        let input = self.prepare_input(input_mask).unwrap_or_else(|| input_mask.to_vec());
+        self.push_history(&input, 1.0);
+        self.chunked = Some(ChunkedRefine {
+            input,
+            seed: self.click_to_pixel(click_x, click_y),
+            stage: ChunkStage::Average,
+            row: 0,
+            binary: vec![0; size],
+            eroded: vec![0; size],
+            isolated: vec![0; size],
+            dilated: vec![0; size],
+            components: 0,
+            start: None,
+        });
+        true
+    }
+
+    /// Advance the chunked refine by up to `max_rows` rows (at least 1) of the
+    /// current stage: averaging and thresholding, erosion, dilation. The flood
+    /// fill is global and always takes one whole step of its own. Returns
+    /// true once every stage is done, or if no chunked refine is running (or
+    /// the frame size changed under it, which abandons it).
+    pub fn refine_mask_step(&mut self, max_rows: usize) -> bool {
+        let Some(mut job) = self.chunked.take() else {
+            return true;
+        };
+        let (w, h) = (self.width, self.height);
+        if job.input.len() != w * h {
+            return true;
+        }
+        let rows = job.row..job.row.saturating_add(max_rows.max(1)).min(h);
+        let morphology = self.config.dilate_after_isolate && self.config.erosion_enabled;
+        match job.stage {
+            ChunkStage::Average => {
+                let total: f32 = self.history_weights.iter().sum();
+                let frames: Vec<Cow<[f32]>> = self.history.iter().map(Frame::decode).collect();
+                for i in rows.start * w..rows.end * w {
+                    let v = if total > 0.0 {
+                        frames.iter().zip(&self.history_weights).map(|(f, &wk)| f[i] * (wk / total)).sum()
+                    } else {
+                        job.input[i]
+                    };
+                    job.binary[i] = (self.alpha_curve_at(v) > self.config.threshold) as u8;
+                }
+            }
+            ChunkStage::Erode if self.config.erosion_enabled => self.erode_rows(&job.binary, &mut job.eroded, self.config.erode_radius, rows.clone()),
+            ChunkStage::Erode => job.eroded[rows.start * w..rows.end * w].copy_from_slice(&job.binary[rows.start * w..rows.end * w]),
+            ChunkStage::Fill => match job.seed {
+                Some((sx, sy)) => {
+                    job.start = self.flood_fill(&job.eroded, &mut job.isolated, sx, sy);
+                    job.components = job.start.is_some() as usize;
+                }
+                None => job.isolated.clone_from(&job.eroded),
+            },
+            ChunkStage::Dilate if morphology => self.dilate_rows(&job.isolated, &mut job.dilated, self.config.dilate_radius, rows.clone()),
+            ChunkStage::Dilate => job.dilated[rows.start * w..rows.end * w].copy_from_slice(&job.isolated[rows.start * w..rows.end * w]),
+            ChunkStage::Done => {}
+        }
+        job.row = rows.end;
+        if job.stage == ChunkStage::Fill || job.row >= h {
+            job.row = 0;
+            job.stage = match job.stage {
+                ChunkStage::Average => ChunkStage::Erode,
+                ChunkStage::Erode => ChunkStage::Fill,
+                ChunkStage::Fill => ChunkStage::Dilate,
+                ChunkStage::Dilate | ChunkStage::Done => ChunkStage::Done,
+            };
+        }
+        let done = job.stage == ChunkStage::Done;
+        self.chunked = Some(job);
+        done
+    }
+
+    /// Finish the chunked refine (running any steps still left in one go) and
+    /// return its mask, updating the statistics like `refine_mask`. Returns an
+    /// empty vec if no chunked refine is running.
+    pub fn refine_mask_finish(&mut self) -> Vec<f32> {
+        while !self.refine_mask_step(usize::MAX) {}
+        let Some(job) = self.chunked.take() else {
+            return Vec::new();
+        };
+        let mut final_mask = self.reapply(&job.input, &job.dilated, &job.isolated);
+        for (v, &p) in final_mask.iter_mut().zip(&self.paint) {
+            match p {
+                1 => *v = 1.0,
+                2 => *v = 0.0,
+                _ => {}
+            }
+        }
+        self.components_selected = job.components;
+        self.last_seed = job.start;
+        self.applied_dilate = if self.config.dilate_after_isolate && self.config.erosion_enabled { self.config.dilate_radius } else { 0 };
+        self.last_threshold = vec![0.0, self.config.threshold, self.config.threshold, self.config.threshold];
+        if self.config.keep_isolated {
+            self.last_isolated = job.isolated;
+        }
+        self.last_binary = job.binary;
+        self.last_eroded = job.eroded;
+        self.update_selection_stats(&final_mask);
+        self.edge_confidence = Self::edge_confidence_of(&final_mask, &job.input);
+        self.last_input = job.input;
+        self.last_final = final_mask.clone();
+        self.to_output_layout(final_mask, self.width, self.height)
+    }
+
+    /// `refine_mask` with the click given as the integer pixel `(click_px,
+    /// click_py)` instead of normalized coordinates, so high-resolution callers
+    /// that already know the exact pixel avoid the float round trip. Out of
+    /// bounds behaves like an out-of-bounds normalized click.
+    pub fn refine_mask_px(&mut self, input_mask: &[f32], click_px: u32, click_py: u32) -> Vec<f32> {
+        let (x, y) = (click_px as usize, click_py as usize);
+        let seed = (x < self.width && y < self.height).then_some((x, y));
+        self.refine_seeded(input_mask, seed.as_slice(), 1.0)
+    }
+
+    /// `refine_mask` with a reliability `weight` for this frame (e.g. lower for
+    /// motion-blurred frames): the temporal average becomes the weighted mean
+    /// of the frames in the history window, each with the weight it was added
+    /// with (1.0 for the other refine calls), so equal weights give the usual
+    /// flat mean. If every weight in the window is zero the latest frame is
+    /// used as-is. Negative weights count as zero.
+    pub fn refine_mask_weighted(&mut self, input_mask: &[f32], weight: f32, click_x: f32, click_y: f32) -> Vec<f32> {
+        let seed = self.click_to_pixel(click_x, click_y);
+        let weight = if weight.is_finite() { weight.max(0.0) } else { 0.0 };
+        self.refine_seeded(input_mask, seed.as_slice(), weight)
+    }
+
+    /// How the history window is combined: 0 = weighted mean (default),
+    /// 1 = per-pixel maximum, a long-exposure style accumulation where anything
+    /// that was foreground during the window stays lit (light trails and
+    /// similar effects). Frames added with weight 0 are left out of the
+    /// maximum. Max mode is meant for effects, not a clean per-frame selection;
+    /// unlike fade-out it keeps the whole trail at full strength, and `reset`
+    /// clears the accumulation. Unknown values fall back to the mean.
+    pub fn set_temporal_mode(&mut self, mode: u32) {
+        self.config.temporal_mode = match mode {
+            1 => TemporalMode::Max,
+            _ => TemporalMode::Mean,
+        };
+    }
+
+    /// Use a different threshold while the temporal average is still built
+    /// from few frames (the first frames after a click, `reset`, or a cut):
+    /// with `n` of `max_history` frames stored, the threshold is
+    /// `start + (threshold - start) * (n - 1) / (max_history - 1)`, easing from
+    /// `start` on the first frame to the configured value once the window is
+    /// full. A stricter `start` (e.g. 0.7) keeps the noisy warm-up frames from
+    /// flickering. It has no effect once the buffer is full, or with a history
+    /// of one frame. A negative or non-finite `start` turns it off (default).
+    pub fn set_warmup_threshold(&mut self, start: f32) {
+        self.config.warmup_threshold = (start.is_finite() && start >= 0.0).then_some(start);
+    }
+
+    /// Keep glitch frames out of the temporal average: a frame whose
+    /// foreground area (input above the threshold) is more than `ratio` times,
+    /// or less than `1 / ratio` times, the mean area of the weighted frames in
+    /// the history is added with weight 0, and `last_status` reports
+    /// `STATUS_OUTLIER` (bit 2). A genuine fast change (a cut, the subject
+    /// leaving) looks the same at first; it is accepted again once the
+    /// rejected frames fill the window, or at once with `set_auto_reset`,
+    /// which flushes the history first. Values of 1 or below turn it off (the
+    /// default).
+    pub fn set_outlier_rejection(&mut self, ratio: f32) {
+        self.config.outlier_ratio = if ratio > 1.0 { ratio } else { 0.0 };
+    }
+
+    /// Change the temporal window to `new_max` frames (at least 1) without
+    /// losing history. Shrinking drops the oldest stored frames (of every
+    /// layer) right away, so the next average uses only the newest `new_max`;
+    /// growing keeps all frames and lets the window fill up as more arrive.
+    pub fn set_max_history(&mut self, new_max: usize) {
+        self.max_history = new_max.max(1);
+        let trim = |history: &mut Vec<Frame>, weights: &mut Vec<f32>| {
+            let excess = history.len().saturating_sub(self.max_history);
+            history.drain(..excess);
+            weights.drain(..excess.min(weights.len()));
+        };
+        trim(&mut self.history, &mut self.history_weights);
+        for layer in &mut self.layers {
+            trim(&mut layer.history, &mut layer.history_weights);
+        }
+    }
+
+    /// Store history frames with `bits` per value to save memory on long
+    /// smoothing windows: 8 (a quarter of the memory) or 16 (half), quantized
+    /// from the input clamped to 0-1 and decoded back to floats only for the
+    /// averaging, which then differs from full precision by at most half a
+    /// step (1/510 for 8 bits). Any other value keeps full `f32` frames (the
+    /// default). Frames already stored, in every layer, are converted.
+    pub fn set_history_precision(&mut self, bits: u8) {
+        self.config.history_bits = if bits == 8 || bits == 16 { bits } else { 32 };
+        let bits = self.config.history_bits;
+        let convert = |history: &mut Vec<Frame>| {
+            for frame in history.iter_mut() {
+                *frame = Frame::encode(&frame.decode(), bits);
+            }
+        };
+        convert(&mut self.history);
+        for layer in &mut self.layers {
+            convert(&mut layer.history);
+        }
+    }
+
+    /// The active layer's temporal history for saving a session:
+    /// `[count, frame_0..., frame_1..., ...]`, the number of stored frames
+    /// followed by each frame's `width * height` values row-major, oldest
+    /// first, decoded to floats whatever `set_history_precision` stores.
+    /// Averaging weights are not included.
+    pub fn export_history(&self) -> Vec<f32> {
+        let mut out = Vec::with_capacity(1 + self.history.len() * self.width * self.height);
+        out.push(self.history.len() as f32);
+        for frame in &self.history {
+            out.extend_from_slice(&frame.decode());
+        }
+        out
+    }
+
+    /// Restore a history saved with `export_history`, so smoothing resumes
+    /// warm after a reload: `frames` is the export without its leading count
+    /// (`count` concatenated frames of `width * height` values, oldest first).
+    /// It replaces the active layer's history; every frame gets weight 1, and
+    /// if `count` exceeds `max_history` only the newest frames are kept. Does
+    /// nothing if `frames.len() != count * width * height`.
+    pub fn import_history(&mut self, frames: &[f32], count: usize) {
+        let size = self.width * self.height;
+        if size == 0 || frames.len() != count * size {
+            return;
+        }
+        let skip = count.saturating_sub(self.max_history);
+        self.history = frames.chunks_exact(size).skip(skip).map(|f| Frame::encode(f, self.config.history_bits)).collect();
+        self.history_weights = vec![1.0; self.history.len()];
+    }
+
+    /// Length of the temporal smoothing in milliseconds rather than frames, for
+    /// variable frame rates; used by `refine_mask_dt_aware`. 0 (default) keeps
+    /// the fixed `max_history` window.
+    pub fn set_smoothing_time(&mut self, ms: f32) {
+        self.config.smoothing_ms = if ms.is_finite() { ms.max(0.0) } else { 0.0 };
+    }
+
+    /// `refine_mask` for a frame that arrived `dt` milliseconds after the
+    /// previous one. The history window becomes
+    /// `clamp(round(smoothing_ms / dt), 1, max_history)` frames, so the
+    /// smoothing spans about the same real time at 24 and 60 fps (e.g. 100 ms
+    /// is 2 frames at 24 fps and 6 at 60 fps); `max_history` stays the upper
+    /// bound on stored frames. Only the newest frames of the window are
+    /// averaged; older ones stay stored, so a short `dt` narrows this frame's
+    /// window without shortening the next one's. Without a smoothing time, or
+    /// with a non-positive `dt`, this is plain `refine_mask`.
+    pub fn refine_mask_dt_aware(&mut self, input_mask: &[f32], dt: f32, click_x: f32, click_y: f32) -> Vec<f32> {
+        if self.config.smoothing_ms <= 0.0 || dt <= 0.0 || !dt.is_finite() {
+            return self.refine_mask(input_mask, click_x, click_y);
+        }
+        let frames = (self.config.smoothing_ms / dt).round().clamp(1.0, self.max_history.max(1) as f32) as usize;
+        let seed = self.click_to_pixel(click_x, click_y);
+        self.refine_frame(input_mask, seed.as_slice(), 1.0, FrameOptions { window: Some(frames), ..FrameOptions::default() })
+    }
+
+    /// `refine_mask` that only selects the part of the clicked component within
+    /// `max_dist` pixels of the click along paths inside it (4-connected steps
+    /// through the eroded map, from the pixel the fill starts at), e.g. an arm
+    /// rather than the whole body. The dilation then regrows the edges as
+    /// usual. A `max_dist` larger than the component equals `refine_mask`;
+    /// negative values select nothing.
+    pub fn refine_mask_geodesic(&mut self, input_mask: &[f32], click_x: f32, click_y: f32, max_dist: f32) -> Vec<f32> {
+        let seed = self.click_to_pixel(click_x, click_y);
+        let options = FrameOptions { geodesic_limit: Some(max_dist), ..FrameOptions::default() };
+        self.refine_frame(input_mask, seed.as_slice(), 1.0, options)
+    }
+
+    /// `refine_mask` with the AI mask first zeroed wherever the luminance of
+    /// `src` (RGBA, `4 * width * height` bytes) is outside
+    /// `[luma_min, luma_max]`, for bright subjects on dark backgrounds (or the
+    /// reverse). Luminance is Rec. 601 luma, `0.299 R + 0.587 G + 0.114 B`,
+    /// scaled to 0-1. The gated mask is what enters the history. If either
+    /// buffer has the wrong size, returns what `set_on_mismatch` selects.
+    pub fn refine_luma_gated(
+        &mut self,
+        input_mask: &[f32],
+        src: &[u8],
+        luma_min: f32,
+        luma_max: f32,
+        click_x: f32,
+        click_y: f32,
+    ) -> Vec<f32> {
+        let size = self.width * self.height;
+        if input_mask.len() != size || src.len() != size * 4 {
+            return self.size_mismatch(input_mask);
+        }
+        let gated: Vec<f32> = input_mask
+            .iter()
+            .zip(self.luminance(src))
+            .map(|(&v, l)| if l >= luma_min && l <= luma_max { v } else { 0.0 })
+            .collect();
+        self.refine_mask(&gated, click_x, click_y)
+    }
+
+    /// `refine_mask` with the selection snapped to superpixels of `src` (RGBA,
+    /// `4 * width * height` bytes), so its boundary follows coherent image
+    /// regions rather than the model's ragged edge. `src` is segmented
+    /// SLIC-style: cluster centres start on a `cell_size` grid and are refined
+    /// by five rounds of k-means over colour and position. Every superpixel
+    /// where most pixels are selected is then taken whole (pixels the refine
+    /// left out get the mean selected value of their superpixel) and every
+    /// other one is dropped, before fade-out. The snapped mask becomes the last
+    /// result for the statistics and the next frame's blending. The segmentation costs
+    /// about five passes over the frame with a few colour distances per pixel
+    /// on top of the refine. A `cell_size` below 2 skips the snapping. If
+    /// either buffer has the wrong size, returns what `set_on_mismatch` selects.
+    pub fn refine_snap_superpixels(
+        &mut self,
+        input_mask: &[f32],
+        src: &[u8],
+        click_x: f32,
+        click_y: f32,
+        cell_size: usize,
+    ) -> Vec<f32> {
+        let seed = self.click_to_pixel(click_x, click_y);
+        self.refine_frame(input_mask, seed.as_slice(), 1.0, FrameOptions { snap: Some((src, cell_size)), ..FrameOptions::default() })
+    }
+
+    /// `refine_mask` with the result quantized to 16 bits (`round(v * 65535)`,
+    /// clamped to 0-1) for HDR alpha pipelines, where 8-bit alpha shows banding.
+    /// Everything up to the quantization is the f32 path.
+    pub fn refine_mask_u16(&mut self, input_mask: &[f32], click_x: f32, click_y: f32) -> Vec<u16> {
+        self.refine_mask(input_mask, click_x, click_y)
+            .iter()
+            .map(|&v| (v.clamp(0.0, 1.0) * 65535.0).round() as u16)
+            .collect()
+    }
+
+    /// `refine_mask` for a 1-bit-per-pixel mask, so both directions can be
+    /// bit-packed. `packed` holds `ceil(width * height / 8)` bytes, pixels in
+    /// row-major order and most significant bit first (pixel 0 is bit 7 of
+    /// byte 0). It is processed as with `set_binary_input`, and the selection
+    /// comes back packed the same way (in the output layout), with any
+    /// non-zero final value as 1 and the padding bits 0. Returns an empty vec
+    /// if `packed` has the wrong length.
+    pub fn refine_mask_bits_in(&mut self, packed: &[u8], click_x: f32, click_y: f32) -> Vec<u8> {
+        let size = self.width * self.height;
+        if packed.len() != size.div_ceil(8) {
+            return Vec::new();
+        }
+        let unpacked: Vec<f32> = (0..size).map(|i| ((packed[i / 8] >> (7 - i % 8)) & 1) as f32).collect();
+        let seed = self.click_to_pixel(click_x, click_y);
+        let refined = self.refine_frame(&unpacked, seed.as_slice(), 1.0, FrameOptions { binary_input: true, ..FrameOptions::default() });
+
+        let mut out = vec![0u8; size.div_ceil(8)];
+        for (i, _) in refined.iter().enumerate().filter(|(_, &v)| v > 0.0) {
+            out[i / 8] |= 0x80 >> (i % 8);
+        }
+        out
+    }
+
+    /// `refine_mask` that returns only what changed, for sending edits over a
+    /// network: a flat `[index0, value0, index1, value1, ...]` list of the
+    /// pixels whose final value differs from the previous frame's, where
+    /// `index` is row-major (`y * width + x`) whatever the output layout and
+    /// `value` is quantized to 16 bits like `refine_mask_u16`
+    /// (`round(v * 65535)`). A pixel counts as changed when its quantized
+    /// value does, so changes under half a step (about 7.6e-6) are dropped.
+    /// The first frame, or the first after `reset`, lists every non-zero pixel.
+    pub fn refine_and_diff(&mut self, input_mask: &[f32], click_x: f32, click_y: f32) -> Vec<u32> {
+        let quantize = |v: f32| (v.clamp(0.0, 1.0) * 65535.0).round() as u32;
+        let prev = self.last_final.clone();
+        self.refine_mask(input_mask, click_x, click_y);
+
+        let mut diff = Vec::new();
+        for (i, &v) in self.last_final.iter().enumerate() {
+            let q = quantize(v);
+            if q != prev.get(i).map_or(0, |&p| quantize(p)) {
+                diff.extend([i as u32, q]);
+            }
+        }
+        diff
+    }
+
+    /// `refine_mask` followed by `apply_to_rgba` with premultiplied alpha, in
+    /// one call for the common "refine then show" path: returns the cutout of
+    /// `src` (RGBA, `4 * width * height` bytes) and leaves the float mask in
+    /// `last_mask` for follow-up queries. Returns an empty vec, without
+    /// refining, if either buffer has the wrong size.
+    pub fn refine_and_cutout(&mut self, input_mask: &[f32], src: &[u8], click_x: f32, click_y: f32) -> Vec<u8> {
+        let size = self.width * self.height;
+        if input_mask.len() != size || src.len() != size * 4 {
+            return Vec::new();
+        }
+        self.refine_mask(input_mask, click_x, click_y);
+        self.apply_to_rgba(src, &self.last_final, true)
+    }
+
+    /// Like `refine_mask` with several clicks, given as `[x0, y0, x1, y1, ...]`
+    /// in the same normalized coordinates; the union of the clicked components
+    /// is kept. A click landing in a component an earlier click already filled
+    /// is skipped. Out-of-bounds clicks are ignored, and if none are left every
+    /// component is kept as in `refine_all`.
+    pub fn refine_multi(&mut self, input_mask: &[f32], clicks: &[f32]) -> Vec<f32> {
+        let seeds: Vec<(usize, usize)> = clicks
+            .chunks_exact(2)
+            .filter_map(|c| self.click_to_pixel(c[0], c[1]))
+            .collect();
+        self.refine_seeded(input_mask, &seeds, 1.0)
+    }
+
+    /// Number of distinct components the clicks of the last refine selected,
+    /// e.g. 1 when three clicks all landed on the same object. 0 when no click
+    /// reached foreground, or the frame was refined without clicks.
+    pub fn distinct_components_selected(&self) -> usize {
+        self.components_selected
+    }
+
+    /// Freeze the current selection: until `unlock_selection`, clicks are
+    /// ignored and each frame keeps the components of the eroded foreground
+    /// that overlap the previous result, the same anchoring `refine_with_prev`
+    /// uses when its click is outside the frame, fed with this refiner's own
+    /// last output. Temporal smoothing carries on as usual. If there is no
+    /// previous selection yet (or after `reset`), the click is used.
+    pub fn lock_selection(&mut self) {
+        self.config.selection_locked = true;
+    }
+
+    /// Resume seeding each frame from the click.
+    pub fn unlock_selection(&mut self) {
+        self.config.selection_locked = false;
+    }
+
+    /// Clean up the whole mask without isolating a single component, for
+    /// automated pipelines with no click: temporal averaging, threshold, the
+    /// erode/dilate opening and soft re-application run as in `refine_mask`,
+    /// but every component that survives the opening is kept.
+    pub fn refine_all(&mut self, input_mask: &[f32]) -> Vec<f32> {
+        self.refine_seeded(input_mask, &[], 1.0)
+    }
+
+    fn refine_seeded(&mut self, input_mask: &[f32], seeds: &[(usize, usize)], weight: f32) -> Vec<f32> {
+        self.refine_frame(input_mask, seeds, weight, FrameOptions::default())
+    }
+
+    /// `refine_seeded` with the per-call `options`. Settings that only hold
+    /// for this frame (the warm-up threshold, a geodesic limit, binary input)
+    /// go into a frame-sized `context` that runs the pipeline instead of this
+    /// refiner, so its own settings are never touched.
+    fn refine_frame(&mut self, input_mask: &[f32], seeds: &[(usize, usize)], weight: f32, options: FrameOptions) -> Vec<f32> {
+        let size = self.width * self.height;
+        self.status = 0;
+        if input_mask.len() != size || options.snap.is_some_and(|(src, _)| src.len() != size * 4) {
+            return self.size_mismatch(input_mask); // Fallback if size mismatch
+        }
+
+        let prepared = self.prepare_input(input_mask);
+        let input_mask = prepared.as_deref().unwrap_or(input_mask);
+
+        if self.config.auto_reset && size > 0 {
+            if let Some(prev) = self.history.last().map(Frame::decode) {
+                let diff: f32 = prev.iter().zip(input_mask).map(|(a, b)| (a - b).abs()).sum();
+                if diff / size as f32 > self.config.auto_reset_sensitivity {
+                    self.reset();
+                    self.status |= STATUS_AUTO_RESET;
+                }
+            }
+        }
+
+        let mut weight = weight;
+        if self.config.outlier_ratio > 0.0 && weight > 0.0 {
+            let area = |m: &[f32]| m.iter().filter(|&&v| v > self.config.threshold).count() as f32;
+            let weighted: Vec<f32> =
+                self.history.iter().zip(&self.history_weights).filter(|(_, &wk)| wk > 0.0).map(|(h, _)| area(&h.decode())).collect();
+            if !weighted.is_empty() {
+                let mean = weighted.iter().sum::<f32>() / weighted.len() as f32;
+                let current = area(input_mask);
+                if current > mean * self.config.outlier_ratio || current * self.config.outlier_ratio < mean {
+                    weight = 0.0;
+                    self.status |= STATUS_OUTLIER;
+                }
+            }
+        }
+
+        // 1. Add to history and calculate temporal average
+        let mut averaged_mask = vec![0.0f32; size];
+        self.push_history(input_mask, weight);
+
+        let first = options.window.map_or(0, |n| self.history.len().saturating_sub(n));
+        let total: f32 = self.history_weights[first..].iter().sum();
+        if self.config.temporal_mode == TemporalMode::Max && total > 0.0 {
+            for (k, _) in self.history_weights.iter().enumerate().skip(first).filter(|(_, &wk)| wk > 0.0) {
+                for (a, &v) in averaged_mask.iter_mut().zip(self.history_frame(k, input_mask).iter()) {
+                    *a = a.max(v);
+                }
+            }
+        } else if total > 0.0 {
+            let newest = self.history.len() - 1;
+            let older = total - self.history_weights[newest];
+            let current = match self.config.current_frame_weight {
+                Some(_) if older <= 0.0 => Some(1.0),
+                Some(_) if self.history_weights[newest] <= 0.0 => Some(0.0),
+                cw => cw,
+            };
+            for (k, &wk) in self.history_weights.iter().enumerate().skip(first).filter(|(_, &wk)| wk > 0.0) {
+                let share = match current {
+                    Some(cw) if k == newest => cw,
+                    Some(cw) => (1.0 - cw) * wk / older,
+                    None => wk / total,
+                };
+                for (a, &v) in averaged_mask.iter_mut().zip(self.history_frame(k, input_mask).iter()) {
+                    *a += v * share;
+                }
+            }
+        } else if !self.history.is_empty() {
+            averaged_mask.copy_from_slice(input_mask);
+        }
+        if self.config.localized_smoothing && self.last_final.len() == size {
+            let prev: Vec<u8> = self.last_final.iter().map(|&v| (v > 0.0) as u8).collect();
+            if prev.contains(&1) {
+                let near = self.dilate(&prev, self.config.dilate_radius.max(1));
+                for ((a, &n), &v) in averaged_mask.iter_mut().zip(&near).zip(input_mask) {
+                    if n == 0 {
+                        *a = v;
+                    }
+                }
+            }
+        }
+        self.deghost_average(&mut averaged_mask, input_mask);
+        self.remap_alpha(&mut averaged_mask);
+
+        if self.config.keep_averaged {
+            self.last_averaged.clone_from(&averaged_mask);
+        }
+
+        let locked: Option<Vec<u8>> = if self.config.selection_locked && self.last_final.len() == size {
+            Some(self.last_final.iter().map(|&v| (v > 0.0) as u8).collect())
+        } else {
+            None
+        };
+        let mut threshold = self.config.threshold;
+        if let Some(start) = self.config.warmup_threshold {
+            if self.history.len() < self.max_history {
+                let t = (self.history.len() - 1) as f32 / (self.max_history - 1) as f32;
+                threshold = start + (threshold - start) * t;
+            }
+        }
+        let (pass_seeds, anchor) = match locked {
+            Some(prev) if prev.contains(&1) => (&[][..], Some(prev)),
+            _ => (seeds, None),
+        };
+        let frame_config = Config {
+            threshold,
+            geodesic_limit: options.geodesic_limit,
+            binary_input: self.config.binary_input || options.binary_input,
+            ..self.config.clone()
+        };
+        let ctx = (frame_config != self.config).then(|| {
+            let mut ctx = self.context(self.width, self.height, frame_config, self.barrier.clone(), self.flood_allowed.clone());
+            ctx.roi = self.roi;
+            ctx
+        });
+        let runner = ctx.as_ref().unwrap_or(self);
+        let prior_source = if self.config.passes > 1 || self.config.debug_layers { averaged_mask.clone() } else { Vec::new() };
+        let mut stages = runner.process_in_roi(input_mask, averaged_mask, pass_seeds, anchor.as_deref());
+        for _ in 1..self.config.passes {
+            // Restrict the next pass to what this one kept of the thresholded mask
+            let prior: Vec<f32> = prior_source
+                .iter()
+                .zip(&stages.final_mask)
+                .zip(&stages.binary)
+                .map(|((&a, &f), &b)| if f > 0.0 && b != 0 { a } else { 0.0 })
+                .collect();
+            let next = runner.process_in_roi(input_mask, prior, pass_seeds, anchor.as_deref());
+            stages = Stages { binary: stages.binary, ..next };
+        }
+        if let Some(ctx) = ctx {
+            self.absorb_caches(ctx);
+        }
+        self.components_selected = stages.components;
+        self.last_seed = stages.seed;
+        self.applied_dilate = stages.dilate;
+        let mode = if self.config.auto_gain { 1.0 } else { 0.0 };
+        self.last_threshold = vec![mode, threshold * stages.relax, threshold, threshold / stages.gain];
+        self.last_input = input_mask.to_vec();
+        let mut layers = Vec::new();
+        if self.config.debug_layers {
+            layers.reserve(5 * size);
+            layers.extend_from_slice(&prior_source);
+            layers.extend(stages.binary.iter().map(|&v| v as f32));
+            layers.extend(stages.isolated.iter().map(|&v| v as f32));
+            layers.extend_from_slice(&stages.final_mask);
+        }
+        self.last_binary = stages.binary;
+        self.last_eroded = stages.eroded;
+        if self.config.keep_isolated {
+            self.last_isolated = stages.isolated;
+        }
+        let mut final_mask = stages.final_mask;
+        self.fill_small_holes(&mut final_mask);
+        if let Some(&(x, y)) = pass_seeds.first().filter(|_| self.config.guarantee_click) {
+            let click_idx = y * self.width + x;
+            final_mask[click_idx] = final_mask[click_idx].max(input_mask[click_idx]);
+        }
+        for (v, &p) in final_mask.iter_mut().zip(&self.paint) {
+            match p {
+                1 => *v = 1.0,
+                2 => *v = 0.0,
+                _ => {}
+            }
+        }
+
+        if self.config.result_blend < 1.0 && self.last_final.len() == size {
+            let k = self.config.result_blend;
+            for (v, &prev) in final_mask.iter_mut().zip(&self.last_final) {
+                *v = k * *v + (1.0 - k) * prev;
+            }
+        }
+        if let Some((src, cell_size)) = options.snap.filter(|&(_, c)| c >= 2) {
+            final_mask = self.snap_to_superpixels(&final_mask, src, cell_size);
+        }
+
+        // 6. Let vanished pixels fade out instead of dropping to zero
+        if self.config.fadeout_frames > 0 {
+            let step = 1.0 / self.config.fadeout_frames as f32;
+            if self.fade.len() != size {
+                self.fade = vec![0.0; size];
+            }
+            for (v, f) in final_mask.iter_mut().zip(self.fade.iter_mut()) {
+                *v = v.max(*f - step);
+                *f = *v;
+            }
+        }
+
+        self.update_selection_stats(&final_mask);
+        self.edge_confidence = Self::edge_confidence_of(&final_mask, input_mask);
+        if self.config.auto_roi {
+            let [x0, y0, x1, y1] = self.last_bbox;
+            let m = self.config.roi_margin;
+            self.roi = (self.last_area > 0)
+                .then(|| [x0.saturating_sub(m), y0.saturating_sub(m), (x1 + m).min(self.width), (y1 + m).min(self.height)]);
+        }
+        if self.config.debug_layers {
+            layers.extend_from_slice(&final_mask);
+            self.last_layers = layers;
+        }
+        self.last_final = final_mask.clone();
+        self.to_output_layout(final_mask, self.width, self.height)
+    }
+
+    /// Take every superpixel of `src` where most pixels of `mask` are selected
+    /// whole (filling its gaps with the mean selected value) and drop the rest.
+    fn snap_to_superpixels(&self, mask: &[f32], src: &[u8], cell_size: usize) -> Vec<f32> {
+        let labels = self.superpixels(src, cell_size);
+        let count = labels.iter().max().map_or(0, |&l| l as usize + 1);
+        let (mut members, mut selected, mut sum) = (vec![0u32; count], vec![0u32; count], vec![0.0f32; count]);
+        for (&l, &v) in labels.iter().zip(mask) {
+            members[l as usize] += 1;
+            if v > 0.0 {
+                selected[l as usize] += 1;
+                sum[l as usize] += v;
+            }
+        }
+        labels
+            .iter()
+            .zip(mask)
+            .map(|(&l, &v)| {
+                let l = l as usize;
+                match (2 * selected[l] > members[l], v > 0.0) {
+                    (true, true) => v,
+                    (true, false) => sum[l] / selected[l] as f32,
+                    (false, _) => 0.0,
+                }
+            })
+            .collect()
+    }
+
+    /// Whether the chunked refine implements every setting in use: the core
+    /// pipeline's may be anything, all the others must be at their defaults.
+    fn chunkable(&self) -> bool {
+        let c = &self.config;
+        let core = Config {
+            border_mode: c.border_mode,
+            spatial_median: c.spatial_median,
+            sanitize_input: c.sanitize_input,
+            deinterlace: c.deinterlace,
+            binary_input: c.binary_input,
+            threshold: c.threshold,
+            erosion_enabled: c.erosion_enabled,
+            erode_radius: c.erode_radius,
+            dilate_after_isolate: c.dilate_after_isolate,
+            dilate_radius: c.dilate_radius,
+            element_shape: c.element_shape,
+            element_length: c.element_length,
+            element_rotation: c.element_rotation,
+            pixel_aspect: c.pixel_aspect,
+            alpha_curve: c.alpha_curve.clone(),
+            alpha_gamma: c.alpha_gamma,
+            hard_output: c.hard_output,
+            confidence_floor: c.confidence_floor,
+            history_bits: c.history_bits,
+            keep_isolated: c.keep_isolated,
+            output_layout: c.output_layout,
+            background_floor: c.background_floor,
+            on_mismatch: c.on_mismatch,
+            bbox_smoothing: c.bbox_smoothing,
+            ..Config::default()
+        };
+        core == *c
+    }
+
+    /// Append a frame to the history with `weight`, dropping the oldest beyond
+    /// `max_history`.
+    fn push_history(&mut self, input_mask: &[f32], weight: f32) {
+        self.history.push(Frame::encode(input_mask, self.config.history_bits));
+        self.history_weights.push(weight);
+        if self.history.len() > self.max_history {
+            let excess = self.history.len() - self.max_history;
+            self.history.drain(..excess);
+            self.history_weights.drain(..excess);
+        }
+    }
+
+    /// Mean of `input_mask` over the soft edge of `final_mask` (values strictly
+    /// between 0 and 1), or 0 without one (`last_edge_confidence`).
+    fn edge_confidence_of(final_mask: &[f32], input_mask: &[f32]) -> f32 {
+        let (mut sum, mut n) = (0.0, 0);
+        for (&v, &c) in final_mask.iter().zip(input_mask) {
+            if v > 0.0 && v < 1.0 {
+                sum += c;
+                n += 1;
+            }
+        }
+        if n > 0 { sum / n as f32 } else { 0.0 }
+    }
+
+    /// Keep only the `n` largest 4-connected components of `mask` (by pixel
+    /// area) and zero the rest. Equal areas are broken in favour of the
+    /// component whose first pixel comes earlier in row-major order.
+    /// Returns the input unchanged if its length is not `width * height`.
+    pub fn keep_largest_n(&self, mask: &[u8], n: usize) -> Vec<u8> {
+        if mask.len() != self.width * self.height {
+            return mask.to_vec();
+        }
+
+        let (labels, count) = self.label_components(mask, self.config.max_components);
+        let mut areas = vec![0usize; count + 1];
+        for &l in &labels {
+            areas[l as usize] += 1;
+        }
+
+        let mut order: Vec<usize> = (1..=count).collect();
+        order.sort_by(|&a, &b| areas[b].cmp(&areas[a]).then(a.cmp(&b)));
+        let mut keep = vec![false; count + 1];
+        for &l in order.iter().take(n) {
+            keep[l] = true;
+        }
+
+        labels.iter().map(|&l| keep[l as usize] as u8).collect()
+    }
+
+    /// Every 4-connected component of `mask` with at least `min_area` pixels
+    /// as its own mask plane, for a "label everything" view with a thumbnail
+    /// per subject. Layout: `[count, plane_0..., plane_1..., ...]`, each plane
+    /// `width * height` values (1.0 inside that component, 0.0 elsewhere),
+    /// in row-major order of the components' first pixels. Components past
+    /// `set_max_components` are not labeled and so not returned. Memory is
+    /// `count * width * height` floats, so filter small specks with
+    /// `min_area` on busy frames. Returns an empty vec if the length is not
+    /// `width * height`.
+    pub fn all_component_masks(&self, mask: &[u8], min_area: u32) -> Vec<f32> {
+        let size = self.width * self.height;
+        if mask.len() != size {
+            return Vec::new();
+        }
+        let (labels, count) = self.label_components(mask, self.config.max_components);
+        let mut areas = vec![0u32; count + 1];
+        for &l in &labels {
+            areas[l as usize] += 1;
+        }
+        let kept: Vec<u32> = (1..=count as u32).filter(|&l| areas[l as usize] >= min_area).collect();
+        let mut out = Vec::with_capacity(1 + kept.len() * size);
+        out.push(kept.len() as f32);
+        for &k in &kept {
+            out.extend(labels.iter().map(|&l| (l == k) as u8 as f32));
+        }
+        out
+    }
+
+    /// End points and branch points of the skeleton of `mask`, for rough limb
+    /// and pose cues from a silhouette. The mask is thinned as for
+    /// `set_preserve_thin`, and each skeleton pixel is classified by the number
+    /// of separate skeleton runs around it among its 8 neighbours: one run is
+    /// an end point (a hand, a foot, the top of the head), three or more a
+    /// branch point (shoulders, hips). Using runs rather than neighbour counts
+    /// keeps the staircase pixels the thinning adds from reading as branches;
+    /// a thick junction may still yield two adjacent branch points. Returns
+    /// `[x, y, kind]` triples in row-major order, `kind` 1 for an end point
+    /// and 2 for a branch point; empty if the length is not `width * height`.
+    pub fn skeleton_keypoints(&self, mask: &[u8]) -> Vec<u32> {
+        let (w, h) = (self.width as i32, self.height as i32);
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let binary: Vec<u8> = mask.iter().map(|&v| (v != 0) as u8).collect();
+        let skel = self.skeleton(&binary);
+        let at = |x: i32, y: i32| -> u8 {
+            match self.wrap_x(x) {
+                Some(nx) if y >= 0 && y < h => skel[(y * w + nx) as usize],
+                _ => 0,
+            }
+        };
+        // Neighbours clockwise from north
+        const RING: [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
+
+        let mut out = Vec::new();
+        for (i, _) in skel.iter().enumerate().filter(|(_, &v)| v != 0) {
+            let (x, y) = (i as i32 % w, i as i32 / w);
+            let ring: Vec<u8> = RING.iter().map(|&(dx, dy)| at(x + dx, y + dy)).collect();
+            let runs = (0..8).filter(|&k| ring[k] == 0 && ring[(k + 1) % 8] != 0).count();
+            let kind = match runs {
+                1 => 1,
+                r if r >= 3 => 2,
+                _ => continue,
+            };
+            out.extend([x as u32, y as u32, kind]);
+        }
+        out
+    }
+
+    /// Which 4-connected components of `mask` touch: two components count as
+    /// adjacent when they meet diagonally or are separated by a gap of at most
+    /// one pixel (some pixels at most 2 apart in both x and y), the places
+    /// where bridge breaking or a watershed split would apply. Components are
+    /// numbered from 1 in row-major order of their first pixel, as in
+    /// `keep_largest_n`. Returns the pairs flattened as `[a0, b0, a1, b1, ...]`
+    /// with `a < b`, sorted, each pair once. Returns an empty vec if the length
+    /// is not `width * height`.
+    pub fn component_adjacency(&self, mask: &[u8]) -> Vec<u32> {
+        let (w, h) = (self.width as i32, self.height as i32);
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let (labels, _) = self.label_components(mask, 0);
+        let mut pairs = Vec::new();
+        for (i, &a) in labels.iter().enumerate().filter(|(_, &l)| l != 0) {
+            let (x, y) = (i as i32 % w, i as i32 / w);
+            for dy in 0..=2 {
+                for dx in -2..=2 {
+                    if dy == 0 && dx <= 0 {
+                        continue;
+                    }
+                    let Some(nx) = self.wrap_x(x + dx) else { continue };
+                    let ny = y + dy;
+                    if ny >= h {
+                        continue;
+                    }
+                    let b = labels[(ny * w + nx) as usize];
+                    if b != 0 && b != a {
+                        pairs.push((a.min(b), a.max(b)));
+                    }
+                }
+            }
+        }
+        pairs.sort_unstable();
+        pairs.dedup();
+        pairs.into_iter().flat_map(|(a, b)| [a, b]).collect()
+    }
+
+    /// Morphological reconstruction by dilation: grow `marker` inside `mask`
+    /// until it stops changing, i.e. keep every 4-connected component of `mask`
+    /// that the marker touches. Propagates from the marker with a queue, which
+    /// reaches the same fixed point as repeated geodesic dilation in one pass.
+    /// Respects the border mode. Returns an empty vec if either length is not
+    /// `width * height`.
+    pub fn reconstruct(&self, marker: &[u8], mask: &[u8]) -> Vec<u8> {
+        let w = self.width;
+        let h = self.height;
+        if marker.len() != w * h || mask.len() != w * h {
+            return Vec::new();
+        }
+
+        let mut out = vec![0u8; w * h];
+        let mut q = std::collections::VecDeque::new();
+        for i in 0..w * h {
+            if marker[i] != 0 && mask[i] != 0 {
+                out[i] = 1;
+                q.push_back(i);
+            }
+        }
+
+        while let Some(idx) = q.pop_front() {
+            let x = (idx % w) as i32;
+            let y = idx / w;
+            let mut neighbours = [None; 4];
+            neighbours[0] = self.wrap_x(x - 1).map(|nx| y * w + nx as usize);
+            neighbours[1] = self.wrap_x(x + 1).map(|nx| y * w + nx as usize);
+            if y > 0 { neighbours[2] = Some(idx - w); }
+            if y < h - 1 { neighbours[3] = Some(idx + w); }
+            for n in neighbours.into_iter().flatten() {
+                if out[n] == 0 && mask[n] != 0 {
+                    out[n] = 1;
+                    q.push_back(n);
+                }
+            }
+        }
+        out
+    }
+
+    /// Soften the alpha edge of `mask` against screen-door artefacts: pixels in
+    /// the transition band (strictly between 0 and 1) are replaced by the mean
+    /// of their `(2 * radius + 1)²` box (clipped to the frame), while solid 0
+    /// and 1 pixels are left crisp. Cheaper than blurring the whole mask. The
+    /// mean is taken in the space chosen by `set_feather_space`.
+    /// Returns `mask` unchanged if its length is not `width * height`.
+    pub fn antialias_edge(&self, mask: &[f32], radius: usize) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        if mask.len() != w * h || radius == 0 {
+            return mask.to_vec();
+        }
+        let lut = if self.config.feather_linear { None } else { Some(self.gamma_lut()) };
+        let lookup = |v: f32, k: usize| -> f32 {
+            let Some(lut) = lut.as_deref() else { return v };
+            let f = v * (lut.len() - 1) as f32;
+            let j = (f as usize).min(lut.len() - 2);
+            let t = f - j as f32;
+            lut[j][k] + (lut[j + 1][k] - lut[j][k]) * t
+        };
+        let mut out = mask.to_vec();
+        for (i, o) in out.iter_mut().enumerate() {
+            if mask[i] <= 0.0 || mask[i] >= 1.0 {
+                continue;
+            }
+            let (x, y) = (i % w, i / w);
+            let (mut sum, mut n) = (0.0, 0);
+            for ny in y.saturating_sub(radius)..=(y + radius).min(h - 1) {
+                for nx in x.saturating_sub(radius)..=(x + radius).min(w - 1) {
+                    sum += lookup(mask[ny * w + nx].clamp(0.0, 1.0), 0);
+                    n += 1;
+                }
+            }
+            *o = lookup(sum / n as f32, 1);
+        }
+        out
+    }
+
+    /// `[v^(1/2.2), v^2.2]` sampled at `FEATHER_LUT_SIZE + 1` evenly spaced
+    /// values over 0-1, built on first use and shared by every later blur.
+    /// Lookups interpolate linearly, so the edge blur does no `powf` per pixel.
+    fn gamma_lut(&self) -> std::cell::Ref<'_, Vec<[f32; 2]>> {
+        if self.feather_lut.borrow().is_empty() {
+            let lut = (0..=FEATHER_LUT_SIZE)
+                .map(|j| {
+                    let v = j as f32 / FEATHER_LUT_SIZE as f32;
+                    [v.powf(1.0 / 2.2), v.powf(2.2)]
+                })
+                .collect();
+            *self.feather_lut.borrow_mut() = lut;
+            self.feather_lut_builds.set(self.feather_lut_builds.get() + 1);
+        }
+        self.feather_lut.borrow()
+    }
+
+    /// Feather the final mask where the geometry was uncertain. Pixels that
+    /// erosion removed and the matching dilation of the eroded map can't bring
+    /// back (thin limbs, ragged or noisy edges) are counted around each pixel,
+    /// within the erode radius, and their share of the thresholded pixels there
+    /// sets that pixel's box blur radius from 0 up to the erode radius. Solid regions, where erosion
+    /// only trimmed a band the dilation restores, stay crisp. Unlike
+    /// `antialias_edge`, which blurs the whole transition band by one radius
+    /// on request, this runs inside every refine and also softens hard edges;
+    /// the two can be combined. Off with `set_hard_output` or without erosion.
+    /// Default off.
+    pub fn set_erosion_guided_feather(&mut self, on: bool) {
+        self.config.erosion_guided_feather = on;
+    }
+
+    /// Space the edge blur of `antialias_edge` averages in. Linear (default)
+    /// blurs the mask values as they are. Otherwise they are gamma-encoded
+    /// (`v^(1/2.2)`) before the blur and decoded afterwards. Decoding pulls
+    /// the blurred band down, so the edge falls off faster toward transparent
+    /// than with a linear blur of the same radius. Switching to linear frees
+    /// the gamma tables.
+    pub fn set_feather_space(&mut self, linear: bool) {
+        self.config.feather_linear = linear;
+        if linear {
+            self.feather_lut.get_mut().clear();
+        }
+    }
+
+    /// Edge strength of `src` (RGBA, `4 * width * height` bytes) for UI
+    /// overlays: the Sobel gradient magnitude of its Rec. 601 luminance,
+    /// divided by the frame's strongest gradient so values are 0-1 (all zero
+    /// for a flat frame). Returns an empty vec if `src` has the wrong size.
+    pub fn sobel_edges(&self, src: &[u8]) -> Vec<f32> {
+        if src.len() != self.width * self.height * 4 {
+            return Vec::new();
+        }
+        let grad = self.sobel(&self.luminance(src));
+        let max = grad.iter().cloned().fold(0.0, f32::max);
+        // Far below a one-level luminance step; only float rounding on a flat frame
+        if max < 1e-4 {
+            return vec![0.0; grad.len()];
+        }
+        grad.iter().map(|&g| g / max).collect()
+    }
+
+    /// Cut the subject out of `src` (RGBA, `4 * width * height` bytes): RGB is
+    /// copied and alpha is set to `mask * 255`. With `premultiply` the RGB
+    /// channels are also multiplied by the mask, as canvas/WebGL pipelines that
+    /// expect premultiplied alpha want, which saves a separate pass there.
+    /// `set_knockout` flips the alpha to `1 - mask`.
+    /// Returns an empty vec if either buffer has the wrong size.
+    pub fn apply_to_rgba(&self, src: &[u8], mask: &[f32], premultiply: bool) -> Vec<u8> {
+        let size = self.width * self.height;
+        if src.len() != size * 4 || mask.len() != size {
+            return Vec::new();
+        }
+
+        let mut out = src.to_vec();
+        for (px, &m) in out.chunks_exact_mut(4).zip(mask) {
+            let m = if self.config.knockout { 1.0 - m.clamp(0.0, 1.0) } else { m.clamp(0.0, 1.0) };
+            if premultiply {
+                for c in &mut px[..3] {
+                    *c = (*c as f32 * m).round() as u8;
+                }
+            }
+            px[3] = (m * 255.0).round() as u8;
+        }
+        out
+    }
+
+    /// Set the background of the returned masks to exactly `v`: every final
+    /// value at or below `max(v, 0.02)` becomes `v`. A small `v` (e.g. 0.03)
+    /// leaves a faint residual alpha some composites look more natural with;
+    /// 0 turns the model's faint haze into a hard zero. Values above that
+    /// cutoff (the subject and its soft edge) are untouched. Applies to the
+    /// masks the refine calls return and to `last_mask`; the statistics,
+    /// `refine_and_diff`, `refine_and_cutout` and the next frame still see the
+    /// unfloored result. A negative or non-finite `v` turns it off (default).
+    pub fn set_background_floor(&mut self, v: f32) {
+        self.config.background_floor = (v.is_finite() && v >= 0.0).then(|| v.min(1.0));
+    }
+
+    /// Punch a hole instead of cutting out: `apply_to_rgba` (and
+    /// `refine_and_cutout`) set alpha to `(1 - mask) * 255`, so the frame
+    /// stays opaque around the subject and whatever lies behind it shows
+    /// through the subject's shape. Unlike inverting the selection, the mask
+    /// and every other output keep describing the subject; only the alpha of
+    /// those RGBA paths flips. Premultiplication uses the flipped alpha.
+    /// Default off.
+    pub fn set_knockout(&mut self, on: bool) {
+        self.config.knockout = on;
+    }
+
+    /// Convert straight-alpha RGBA to premultiplied: each of R, G and B is
+    /// multiplied by `alpha / 255` and rounded; alpha is kept. Works on any
+    /// number of pixels, not just a full frame. Returns an empty vec if the
+    /// length is not a multiple of 4.
+    pub fn premultiply(&self, rgba: &[u8]) -> Vec<u8> {
+        if !rgba.len().is_multiple_of(4) {
+            return Vec::new();
+        }
+        let mut out = rgba.to_vec();
+        for px in out.chunks_exact_mut(4) {
+            let a = px[3] as f32 / 255.0;
+            for c in &mut px[..3] {
+                *c = (*c as f32 * a).round() as u8;
+            }
+        }
+        out
+    }
+
+    /// Convert premultiplied RGBA back to straight alpha: each of R, G and B is
+    /// divided by `alpha / 255`, rounded and clamped to 255 (a channel above its
+    /// alpha wasn't validly premultiplied). A fully transparent pixel comes
+    /// back as all zeros rather than dividing by zero; the colour it had is
+    /// lost either way. Returns an empty vec if the length is not a multiple
+    /// of 4.
+    pub fn unpremultiply(&self, rgba: &[u8]) -> Vec<u8> {
+        if !rgba.len().is_multiple_of(4) {
+            return Vec::new();
+        }
+        let mut out = rgba.to_vec();
+        for px in out.chunks_exact_mut(4) {
+            let a = px[3] as f32;
+            for c in &mut px[..3] {
+                *c = if a == 0.0 { 0 } else { (*c as f32 * 255.0 / a).round().min(255.0) as u8 };
+            }
+        }
+        out
+    }
+
+    /// `mask` as a float RGBA buffer with R = G = B = A = mask, i.e. white
+    /// premultiplied by the mask, ready to upload as a float texture (e.g.
+    /// `RGBA32F`) without the 8-bit round trip of an ImageData. Values are
+    /// passed through unclamped at full precision. Returns an empty vec if the
+    /// length is not `width * height`.
+    pub fn mask_to_float_rgba(&self, mask: &[f32]) -> Vec<f32> {
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        mask.iter().flat_map(|&v| [v; 4]).collect()
+    }
+
+    /// Trimap of `mask` for a matting model: the mask is thresholded, pixels
+    /// that survive erosion by `erode_r` are foreground (255), pixels outside
+    /// its dilation by `dilate_r` are background (0), and the band between is
+    /// unknown (128). Both use the current structuring element and border
+    /// mode; negative radii count as 0. Returns an empty vec if the length is
+    /// not `width * height`.
+    pub fn generate_trimap(&self, mask: &[f32], erode_r: i32, dilate_r: i32) -> Vec<u8> {
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let binary = self.threshold_mask(mask);
+        let inner = self.erode(&binary, erode_r.max(0));
+        let outer = self.dilate(&binary, dilate_r.max(0));
+        inner
+            .iter()
+            .zip(&outer)
+            .map(|(&i, &o)| match (i, o) {
+                (1, _) => TRIMAP_FOREGROUND,
+                (_, 0) => TRIMAP_BACKGROUND,
+                _ => TRIMAP_UNKNOWN,
+            })
+            .collect()
+    }
+
+    /// `generate_trimap` for `count` masks stacked frame after frame in
+    /// `masks`, in one call to spare the per-frame call overhead when exporting
+    /// a clip. The trimaps are concatenated in the same order, each
+    /// `width * height` bytes, row-major. Returns an empty vec unless
+    /// `masks.len() == count * width * height`.
+    pub fn generate_trimap_batch(&self, masks: &[f32], count: usize, erode_r: i32, dilate_r: i32) -> Vec<u8> {
+        let size = self.width * self.height;
+        if size == 0 || masks.len() != count * size {
+            return Vec::new();
+        }
+        masks.chunks_exact(size).flat_map(|m| self.generate_trimap(m, erode_r, dilate_r)).collect()
+    }
+
+    /// Check a trimap before handing it to a matting model: returns
+    /// `[foreground, background, unknown, band_width]`, the pixel counts per
+    /// class and the unknown band's average width in whole pixels. Codes are
+    /// 255 = foreground, 0 = background and 128 = unknown; any other value
+    /// counts as unknown. The width is the band's area divided by the mean of
+    /// its two edge lengths (band pixels 4-adjacent to foreground, and to
+    /// background), 0 without a band. Useful for tuning the erode and dilate
+    /// radii so the band covers the true edge without swallowing the subject.
+    /// Returns an empty vec if the length is not `width * height`.
+    pub fn trimap_band_stats(&self, trimap: &[u8]) -> Vec<u32> {
+        let (w, h) = (self.width, self.height);
+        if trimap.len() != w * h {
+            return Vec::new();
+        }
+        let class = |v: u8| match v {
+            TRIMAP_FOREGROUND => TRIMAP_FOREGROUND,
+            TRIMAP_BACKGROUND => TRIMAP_BACKGROUND,
+            _ => TRIMAP_UNKNOWN,
+        };
+        let (mut fg, mut bg, mut unknown, mut inner, mut outer) = (0u32, 0u32, 0u32, 0u32, 0u32);
+        for (i, &v) in trimap.iter().enumerate() {
+            match class(v) {
+                TRIMAP_FOREGROUND => fg += 1,
+                TRIMAP_BACKGROUND => bg += 1,
+                _ => {
+                    unknown += 1;
+                    let (x, y) = (i % w, i / w);
+                    let mut neighbours = Vec::with_capacity(4);
+                    if x > 0 { neighbours.push(i - 1); }
+                    if x < w - 1 { neighbours.push(i + 1); }
+                    if y > 0 { neighbours.push(i - w); }
+                    if y < h - 1 { neighbours.push(i + w); }
+                    inner += neighbours.iter().any(|&n| class(trimap[n]) == TRIMAP_FOREGROUND) as u32;
+                    outer += neighbours.iter().any(|&n| class(trimap[n]) == TRIMAP_BACKGROUND) as u32;
+                }
+            }
+        }
+        let edges = (inner + outer) as f32 / 2.0;
+        let band_width = if edges > 0.0 { (unknown as f32 / edges).round() as u32 } else { 0 };
+        vec![fg, bg, unknown, band_width]
+    }
+
+    /// Snap every alpha value of `mask` to the nearest of `stops` (e.g.
+    /// `[0.0, 0.5, 1.0]`) for cel-shaded, hard-stepped compositing. Unlike
+    /// dithering this is deterministic and the levels can sit anywhere; a value
+    /// exactly halfway between two stops takes the lower one. Returns `mask`
+    /// unchanged if `stops` is empty, not sorted ascending, or has a value
+    /// outside 0-1.
+    pub fn posterize_alpha(&self, mask: &[f32], stops: &[f32]) -> Vec<f32> {
+        let valid = !stops.is_empty()
+            && stops.iter().all(|s| (0.0..=1.0).contains(s))
+            && stops.windows(2).all(|p| p[0] <= p[1]);
+        if !valid {
+            return mask.to_vec();
+        }
+        mask.iter()
+            .map(|&v| {
+                // First stop at or above v, then whichever neighbour is closer
+                let k = stops.partition_point(|&s| s < v);
+                match (k.checked_sub(1).map(|j| stops[j]), stops.get(k)) {
+                    (Some(lo), Some(&hi)) => if v - lo <= hi - v { lo } else { hi },
+                    (Some(lo), None) => lo,
+                    (None, Some(&hi)) => hi,
+                    (None, None) => v,
+                }
+            })
+            .collect()
+    }
+
+    /// Draw the selection outline of `mask` over `src` (RGBA,
+    /// `4 * width * height` bytes) as an opaque `(r, g, b)` stroke. The stroke
+    /// is the morphological inner gradient, `mask` minus its erosion by
+    /// `thickness` with the current structuring element, so it lies just
+    /// inside the subject and follows holes too; the frame edge counts as a
+    /// boundary. `thickness` is clamped to 1 up to the larger frame side.
+    /// Returns an empty vec if either buffer has the wrong size.
+    pub fn draw_outline_rgba(&self, src: &[u8], mask: &[u8], r: u8, g: u8, b: u8, thickness: i32) -> Vec<u8> {
+        let size = self.width * self.height;
+        if src.len() != size * 4 || mask.len() != size {
+            return Vec::new();
+        }
+        let thickness = thickness.clamp(1, self.width.max(self.height).max(1) as i32);
+        let binary: Vec<u8> = mask.iter().map(|&v| (v != 0) as u8).collect();
+        let inner = self.erode(&binary, thickness);
+
+        let mut out = src.to_vec();
+        for ((px, &m), &e) in out.chunks_exact_mut(4).zip(&binary).zip(&inner) {
+            if m != 0 && e == 0 {
+                px.copy_from_slice(&[r, g, b, 255]);
+            }
+        }
+        out
+    }
+
+    /// Portrait-style background defocus of `src` (RGBA, `4 * width * height`
+    /// bytes): the background is box-blurred over `(2 * radius + 1)²` and mixed
+    /// back with the sharp image using `mask` as the weight, so the subject
+    /// (mask 1) stays sharp and the soft edge blends between the two. The blur
+    /// averages background pixels only (weighted by `1 - mask`), so the
+    /// subject's colours don't halo into its surroundings. Alpha is copied.
+    /// Returns an empty vec if either buffer has the wrong size.
+    pub fn blur_background(&self, src: &[u8], mask: &[f32], radius: usize) -> Vec<u8> {
+        let (w, h) = (self.width, self.height);
+        let size = w * h;
+        if src.len() != size * 4 || mask.len() != size {
+            return Vec::new();
+        }
+
+        // Planes of background-weighted R, G, B and the weight itself
+        let mut planes: Vec<Vec<f32>> = vec![vec![0.0; size]; 4];
+        for (i, &m) in mask.iter().enumerate() {
+            let bg = 1.0 - m.clamp(0.0, 1.0);
+            for c in 0..3 {
+                planes[c][i] = src[i * 4 + c] as f32 * bg;
+            }
+            planes[3][i] = bg;
+        }
+        let r = radius as isize;
+        let box_pass = |plane: &[f32], horizontal: bool| -> Vec<f32> {
+            let (len, lines) = if horizontal { (w, h) } else { (h, w) };
+            let at = |line: usize, k: usize| if horizontal { line * w + k } else { k * w + line };
+            let mut out = vec![0.0f32; size];
+            for line in 0..lines {
+                let mut sum = 0.0;
+                for k in 0..r.min(len as isize) {
+                    sum += plane[at(line, k as usize)];
+                }
+                for k in 0..len as isize {
+                    if k + r < len as isize {
+                        sum += plane[at(line, (k + r) as usize)];
+                    }
+                    if k - r > 0 {
+                        sum -= plane[at(line, (k - r - 1) as usize)];
+                    }
+                    out[at(line, k as usize)] = sum;
+                }
+            }
+            out
+        };
+        let blurred: Vec<Vec<f32>> = planes.iter().map(|p| box_pass(&box_pass(p, true), false)).collect();
+
+        let mut out = src.to_vec();
+        for (i, px) in out.chunks_exact_mut(4).enumerate() {
+            let weight = blurred[3][i];
+            if weight <= 1e-6 {
+                continue;
+            }
+            let m = mask[i].clamp(0.0, 1.0);
+            for c in 0..3 {
+                let soft = blurred[c][i] / weight;
+                px[c] = (src[i * 4 + c] as f32 * m + soft * (1.0 - m)).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        out
+    }
+
+    /// Remove the background-coloured fringe left on soft cutout edges.
+    ///
+    /// The background colour is estimated as the mean RGB of `src` (RGBA,
+    /// `4 * width * height` bytes) over the pixels just outside the mask: those
+    /// with mask 0 that have a non-zero 8-neighbour. In the soft-edge band
+    /// (mask strictly between 0 and 1) the part of each pixel's chroma (its
+    /// colour minus its grey level) pointing toward the background's chroma is
+    /// taken out, scaled by `strength` (0-1) and by `1 - mask`, so the faint
+    /// outer edge is cleaned most and the grey level is kept. Strength 0 is a
+    /// no-op, as is a background with no chroma. Alpha is copied. Returns an
+    /// empty vec if either buffer has the wrong size.
+    pub fn despill_edges(&self, src: &[u8], mask: &[f32], strength: f32) -> Vec<u8> {
+        let (w, h) = (self.width, self.height);
+        let size = w * h;
+        if src.len() != size * 4 || mask.len() != size {
+            return Vec::new();
+        }
+        let mut out = src.to_vec();
+        let strength = strength.clamp(0.0, 1.0);
+        if strength == 0.0 {
+            return out;
+        }
+
+        let (mut sum, mut n) = ([0.0f32; 3], 0);
+        for (i, &m) in mask.iter().enumerate() {
+            if m > 0.0 {
+                continue;
+            }
+            let (x, y) = ((i % w) as i32, (i / w) as i32);
+            let outside = (-1..=1).any(|dy| {
+                (-1..=1).any(|dx| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx >= 0 && ny >= 0 && nx < w as i32 && ny < h as i32 && mask[ny as usize * w + nx as usize] > 0.0
+                })
+            });
+            if outside {
+                for (c, s) in sum.iter_mut().enumerate() {
+                    *s += src[i * 4 + c] as f32;
+                }
+                n += 1;
+            }
+        }
+        if n == 0 {
+            return out;
+        }
+        let bg = sum.map(|s| s / n as f32);
+        let grey = |p: [f32; 3]| (p[0] + p[1] + p[2]) / 3.0;
+        let mut chroma = bg.map(|c| c - grey(bg));
+        let len = chroma.iter().map(|c| c * c).sum::<f32>().sqrt();
+        if len < 1e-3 {
+            return out;
+        }
+        chroma.iter_mut().for_each(|c| *c /= len);
+
+        for (px, &m) in out.chunks_exact_mut(4).zip(mask) {
+            if m <= 0.0 || m >= 1.0 {
+                continue;
+            }
+            let p = [px[0] as f32, px[1] as f32, px[2] as f32];
+            let g = grey(p);
+            let spill = (0..3).map(|c| (p[c] - g) * chroma[c]).sum::<f32>().max(0.0);
+            let amount = spill * strength * (1.0 - m);
+            for c in 0..3 {
+                px[c] = (p[c] - amount * chroma[c]).round().clamp(0.0, 255.0) as u8;
+            }
+        }
+        out
+    }
+
+    /// Nudge the 0.5 crossing of `mask` toward the strongest nearby image edge
+    /// in `src` (RGBA, `4 * width * height` bytes). For every boundary pixel the
+    /// search runs along the mask's normal, up to `search` pixels inward and
+    /// outward, for the maximum Sobel luminance gradient; the boundary is then
+    /// grown or shrunk to that position. Edges weaker than 10% of the frame's
+    /// strongest gradient are ignored so flat regions keep the model's edge.
+    /// Returns `mask` unchanged if either buffer has the wrong size.
+    pub fn snap_to_edges(&self, mask: &[f32], src: &[u8], search: usize) -> Vec<f32> {
+        let w = self.width as i32;
+        let h = self.height as i32;
+        let size = self.width * self.height;
+        if mask.len() != size || src.len() != 4 * size {
+            return mask.to_vec();
+        }
+
+        let grad = self.sobel(&self.luminance(src));
+        let min_edge = 0.1 * grad.iter().cloned().fold(0.0, f32::max);
+        let at = |x: i32, y: i32| -> Option<usize> {
+            (x >= 0 && x < w && y >= 0 && y < h).then(|| (y * w + x) as usize)
+        };
+
+        let mut out = mask.to_vec();
+        for y in 0..h {
+            for x in 0..w {
+                let idx = (y * w + x) as usize;
+                if mask[idx] < 0.5 {
+                    continue;
+                }
+                let is_boundary = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    .iter()
+                    .any(|&(dx, dy)| at(x + dx, y + dy).is_some_and(|n| mask[n] < 0.5));
+                if !is_boundary {
+                    continue;
+                }
+
+                // Outward normal from the mask's own gradient.
+                let sample = |x: i32, y: i32| at(x, y).map_or(0.0, |n| mask[n]);
+                let nx = sample(x - 1, y) - sample(x + 1, y);
+                let ny = sample(x, y - 1) - sample(x, y + 1);
+                let len = (nx * nx + ny * ny).sqrt();
+                if len == 0.0 {
+                    continue;
+                }
+                let (nx, ny) = (nx / len, ny / len);
+                let step = |t: i32| at(x + (nx * t as f32).round() as i32, y + (ny * t as f32).round() as i32);
+
+                let mut best_t = 0;
+                let mut best = grad[idx];
+                for t in -(search as i32)..=search as i32 {
+                    if let Some(n) = step(t) {
+                        if grad[n] > best {
+                            best = grad[n];
+                            best_t = t;
+                        }
+                    }
+                }
+                if best < min_edge || best_t == 0 {
+                    continue;
+                }
+
+                if best_t > 0 {
+                    for t in 1..=best_t {
+                        if let Some(n) = step(t) {
+                            out[n] = out[n].max(mask[idx]);
+                        }
+                    }
+                } else {
+                    let outside = step(1).map_or(0.0, |n| mask[n]);
+                    for t in best_t..=0 {
+                        if let Some(n) = step(t) {
+                            out[n] = out[n].min(outside);
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    /// The selection outline of `mask` as SVG path data, ready for the `d`
+    /// attribute of a `<path>`: one `M x y L ... Z` subpath per ring from
+    /// `contours_with_holes` (every component's outer ring followed by its
+    /// holes, simplified at `epsilon` pixels), in pixel coordinates. The ring
+    /// windings let the holes cut out under either `fill-rule`. Returns an
+    /// empty string if there is no foreground or the length is wrong.
+    pub fn to_svg_path(&self, mask: &[u8], epsilon: f32) -> String {
+        let mut d = String::new();
+        for ring in self.contours_with_holes(mask, epsilon) {
+            for (k, p) in ring.chunks_exact(2).enumerate() {
+                if !d.is_empty() {
+                    d.push(' ');
+                }
+                d.push_str(&format!("{} {} {}", if k == 0 { 'M' } else { 'L' }, p[0], p[1]));
+            }
+            d.push_str(" Z");
+        }
+        d
+    }
+
+    /// Subpixel outlines of `mask` at the `iso` level (0.5 for the usual cut-off)
+    /// by marching squares, for smooth vector export. Vertices lie on the lines
+    /// between pixel centres (pixel `(x, y)` is centred at `(x + 0.5, y + 0.5)`),
+    /// placed by linear interpolation, and the frame outside counts as below
+    /// `iso` so every contour is closed. Saddle cells are resolved by the mean
+    /// of their four corners, so diagonal neighbours above `iso` join when the
+    /// cell centre is above it too.
+    ///
+    /// All contours are packed into one list, each as its vertex count `n`
+    /// followed by `n` `(x, y)` pairs. Returns an empty vec if the length is not
+    /// `width * height`.
+    pub fn iso_contour(&self, mask: &[f32], iso: f32) -> Vec<f32> {
+        let (w, h) = (self.width as i32, self.height as i32);
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+        let value = |x: i32, y: i32| {
+            if x < 0 || y < 0 || x >= w || y >= h { iso - 1.0 } else { mask[(y * w + x) as usize] }
+        };
+
+        // Edges are keyed over the padded corner grid: horizontal edge (x, y)
+        // runs from corner (x, y) to (x + 1, y), vertical from (x, y) to (x, y + 1).
+        let gw = w + 2;
+        let key = |x: i32, y: i32, vertical: bool| (((y + 1) * gw + x + 1) * 2 + vertical as i32) as usize;
+        let point = |k: usize| {
+            let (g, vertical) = ((k / 2) as i32, k % 2 == 1);
+            let (x, y) = (g % gw - 1, g / gw - 1);
+            let (nx, ny) = if vertical { (x, y + 1) } else { (x + 1, y) };
+            let (a, b) = (value(x, y), value(nx, ny));
+            let t = if b != a { ((iso - a) / (b - a)).clamp(0.0, 1.0) } else { 0.5 };
+            (x as f32 + 0.5 + t * (nx - x) as f32, y as f32 + 0.5 + t * (ny - y) as f32)
+        };
+
+        let mut segments = Vec::new();
+        for cy in -1..h {
+            for cx in -1..w {
+                let c = [value(cx, cy), value(cx + 1, cy), value(cx + 1, cy + 1), value(cx, cy + 1)];
+                let case = c.iter().fold(0, |acc, &v| acc << 1 | (v > iso) as u8);
+                let centre_in = c.iter().sum::<f32>() / 4.0 > iso;
+                let (t, r, b, l) = (key(cx, cy, false), key(cx + 1, cy, true), key(cx, cy + 1, false), key(cx, cy, true));
+                // Bits are top-left, top-right, bottom-right, bottom-left
+                match case {
+                    1 | 14 => segments.push((l, b)),
+                    2 | 13 => segments.push((b, r)),
+                    3 | 12 => segments.push((l, r)),
+                    4 | 11 => segments.push((t, r)),
+                    6 | 9 => segments.push((t, b)),
+                    7 | 8 => segments.push((t, l)),
+                    5 if centre_in => segments.extend([(t, l), (b, r)]),
+                    5 => segments.extend([(l, b), (t, r)]),
+                    10 if centre_in => segments.extend([(t, r), (l, b)]),
+                    10 => segments.extend([(t, l), (b, r)]),
+                    _ => {}
+                }
+            }
+        }
+
+        // Every crossed edge is shared by exactly two segments; chain them.
+        let mut at_edge = vec![[usize::MAX; 2]; (gw * (h + 2) * 2) as usize];
+        for (i, &(a, b)) in segments.iter().enumerate() {
+            for e in [a, b] {
+                let slot = if at_edge[e][0] == usize::MAX { 0 } else { 1 };
+                at_edge[e][slot] = i;
+            }
+        }
+        let mut used = vec![false; segments.len()];
+        let mut out = Vec::new();
+        for start in 0..segments.len() {
+            if used[start] {
+                continue;
+            }
+            let header = out.len();
+            out.push(0.0);
+            let mut n = 0;
+            let first = segments[start].0;
+            let mut edge = first;
+            let mut seg = start;
+            loop {
+                used[seg] = true;
+                let (x, y) = point(edge);
+                out.extend_from_slice(&[x, y]);
+                n += 1;
+                let (a, b) = segments[seg];
+                edge = if a == edge { b } else { a };
+                if edge == first {
+                    break;
+                }
+                match at_edge[edge].iter().find(|&&s| s != seg && s != usize::MAX && !used[s]) {
+                    Some(&s) => seg = s,
+                    None => break,
+                }
+            }
+            out[header] = n as f32;
+        }
+        out
+    }
+
+    /// Serialize a mask as a compact, self-describing byte buffer for saving.
+    ///
+    /// Layout: the magic bytes `SRM1`, width and height as little-endian `u32`,
+    /// then `(run_length, value)` byte pairs run-length encoding the mask
+    /// quantized to 8 bits (`round(v * 255)`, clamped to 0-1). Runs are 1-255
+    /// pixels long. Returns an empty vec if the length is not `width * height`.
+    pub fn encode_mask(&self, mask: &[f32]) -> Vec<u8> {
+        if mask.len() != self.width * self.height {
+            return Vec::new();
+        }
+
+        let mut out = Vec::with_capacity(12 + mask.len() / 8);
+        out.extend_from_slice(b"SRM1");
+        out.extend_from_slice(&(self.width as u32).to_le_bytes());
+        out.extend_from_slice(&(self.height as u32).to_le_bytes());
+
+        let mut values = mask.iter().map(|&v| (v.clamp(0.0, 1.0) * 255.0).round() as u8).peekable();
+        while let Some(v) = values.next() {
+            let mut run = 1u8;
+            while run < u8::MAX && values.peek() == Some(&v) {
+                values.next();
+                run += 1;
+            }
+            out.push(run);
+            out.push(v);
+        }
+        out
+    }
+
+    /// Decode a buffer written by `encode_mask` back into 0-1 floats. The result
+    /// has the width and height stored in the header, which need not match this
+    /// refiner. Returns an empty vec if the buffer is malformed.
+    pub fn decode_mask(&self, bytes: &[u8]) -> Vec<f32> {
+        if bytes.len() < 12 || &bytes[..4] != b"SRM1" || !(bytes.len() - 12).is_multiple_of(2) {
+            return Vec::new();
+        }
+        let w = u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]) as usize;
+        let h = u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]) as usize;
+        let runs = &bytes[12..];
+        // Check the header against the runs before allocating anything from it
+        let total: usize = runs.chunks_exact(2).map(|pair| pair[0] as usize).sum();
+        if w.checked_mul(h) != Some(total) {
+            return Vec::new();
+        }
+
+        let mut out = Vec::new();
+        for pair in runs.chunks_exact(2) {
+            out.extend(std::iter::repeat_n(pair[1] as f32 / 255.0, pair[0] as usize));
+        }
+        out
+    }
+
+    /// Expand a run-length encoded binary mask straight into an RGBA frame
+    /// (`4 * width * height` bytes) for drawing: foreground pixels get the
+    /// colour `(r, g, b, a)` and background pixels are transparent black.
+    /// `rle` holds alternating run lengths in row-major order, starting with
+    /// background (`[bg, fg, bg, fg, ...]`, as in uncompressed COCO RLE; a
+    /// mask starting with foreground begins with a 0 run). Returns an empty
+    /// vec unless the runs add up to exactly `width * height` pixels.
+    pub fn rle_to_rgba(&self, rle: &[u32], r: u8, g: u8, b: u8, a: u8) -> Vec<u8> {
+        let size = self.width * self.height;
+        if rle.iter().map(|&n| n as u64).sum::<u64>() != size as u64 {
+            return Vec::new();
+        }
+        let mut out = vec![0u8; size * 4];
+        let mut start = 0;
+        for (k, &n) in rle.iter().enumerate() {
+            let end = start + n as usize;
+            if k % 2 == 1 {
+                for px in out[start * 4..end * 4].chunks_exact_mut(4) {
+                    px.copy_from_slice(&[r, g, b, a]);
+                }
+            }
+            start = end;
+        }
+        out
+    }
+
+    /// Suggest a structuring element (a `set_element_shape` id) for breaking
+    /// the bridges between touching objects in `mask` (non-zero = foreground).
+    ///
+    /// A one-shot heuristic for setting up a scene, not a per-frame call:
+    /// erodes the mask at radius 2 with the circle, horizontal line, vertical
+    /// line and square in turn (with the current pixel aspect and border
+    /// mode) and returns the one that adds the most 4-connected components,
+    /// earlier candidates winning ties. A shape that erases the original
+    /// largest component entirely is rejected. Returns 0 (circle) when no
+    /// shape separates anything or the length is not `width * height`.
+    pub fn suggest_element(&self, mask: &[u8]) -> u32 {
+        if mask.len() != self.width * self.height {
+            return 0;
+        }
+        let mut trial = SubjectRefiner::new(self.width, self.height, 1);
+        trial.config.border_mode = self.config.border_mode;
+        trial.set_pixel_aspect(self.config.pixel_aspect);
+        let binary: Vec<u8> = mask.iter().map(|&v| (v != 0) as u8).collect();
+        let (labels, before) = trial.label_components(&binary, 0);
+        let mut areas = vec![0usize; before + 1];
+        for &l in &labels {
+            areas[l as usize] += 1;
+        }
+        let largest = (1..=before).max_by_key(|&l| (areas[l], std::cmp::Reverse(l))).unwrap_or(0) as u32;
+
+        let mut best = (0, 0);
+        for shape in [0, 2, 3, 1] {
+            trial.set_element_shape(shape, 0);
+            let eroded = trial.erode(&binary, SUGGEST_ELEMENT_RADIUS);
+            let survives = largest == 0 || eroded.iter().zip(&labels).any(|(&e, &l)| e != 0 && l == largest);
+            let (_, after) = trial.label_components(&eroded, 0);
+            let gain = after.saturating_sub(before);
+            if survives && gain > best.1 {
+                best = (shape, gain);
+            }
+        }
+        best.0
+    }
+
+    /// Suggest an erosion radius for `mask` as a starting point for new users.
+    ///
+    /// Heuristic, not a guarantee: thresholds the mask, takes the distance
+    /// transform of the foreground and looks at its ridge (local maxima, i.e.
+    /// the half-widths of the subject's parts). Half of the 25th percentile
+    /// ridge value is small enough to keep thin parts such as arms intact while
+    /// still cutting narrower bridges. Clamped to 1-15; returns the default of 5
+    /// for an empty or wrongly sized mask.
+    pub fn suggest_kernel(&self, mask: &[f32]) -> usize {
+        const DEFAULT: usize = 5;
+        let w = self.width as i32;
+        let h = self.height as i32;
+        if mask.len() != self.width * self.height {
+            return DEFAULT;
+        }
+
+        let dist = self.distance_transform(&self.threshold_mask(mask), true);
+        let mut ridge = Vec::new();
+        for y in 0..h {
+            for x in 0..w {
+                let d = dist[(y * w + x) as usize];
+                if d < 1.0 {
+                    continue;
+                }
+                let is_peak = (-1..=1).all(|dy| {
+                    (-1..=1).all(|dx| {
+                        let (nx, ny) = (x + dx, y + dy);
+                        nx < 0 || ny < 0 || nx >= w || ny >= h || dist[(ny * w + nx) as usize] <= d
+                    })
+                });
+                if is_peak {
+                    ridge.push(d);
+                }
+            }
+        }
+        if ridge.is_empty() {
+            return DEFAULT;
+        }
+
+        ridge.sort_unstable_by(|a, b| a.total_cmp(b));
+        let half_width = ridge[ridge.len() / 4];
+        ((half_width * 0.5).round() as usize).clamp(1, 15)
+    }
+
+    /// Refine one tile of a large frame so tiles can be spread over several
+    /// workers. `input_mask` is the full frame; the tile is read together with
+    /// a `halo` of surrounding pixels (clamped to the frame) so erosion and
+    /// dilation are correct at tile edges, and only the `tile_w * tile_h`
+    /// interior is returned, row-major.
+    ///
+    /// Limitations: tiles keep no temporal history, so each tile is refined
+    /// from this frame alone. Flood fill cannot follow the subject across tile
+    /// boundaries: the tile containing the click isolates the component it can
+    /// see within its halo, and other tiles keep every component (the same
+    /// fallback as an out-of-bounds click). Keep the halo at least the erode
+    /// radius plus the dilate radius for seamless tiles. Returns an empty vec if the frame size is
+    /// wrong or the tile does not fit inside the frame.
+    #[allow(clippy::too_many_arguments)]
+    pub fn refine_tile(
+        &mut self,
+        input_mask: &[f32],
+        tile_x: usize,
+        tile_y: usize,
+        tile_w: usize,
+        tile_h: usize,
+        halo: usize,
+        click_x: f32,
+        click_y: f32,
+    ) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        if input_mask.len() != w * h || tile_w == 0 || tile_h == 0 || tile_x + tile_w > w || tile_y + tile_h > h {
+            return Vec::new();
+        }
+
+        let rx0 = tile_x.saturating_sub(halo);
+        let ry0 = tile_y.saturating_sub(halo);
+        let rx1 = (tile_x + tile_w + halo).min(w);
+        let ry1 = (tile_y + tile_h + halo).min(h);
+        let (rw, rh) = (rx1 - rx0, ry1 - ry0);
+        let crop = |buf: &[u8]| -> Vec<u8> {
+            (ry0..ry1).flat_map(|y| buf[y * w + rx0..y * w + rx1].iter().copied()).collect()
+        };
+        let region: Vec<f32> = (ry0..ry1)
+            .flat_map(|y| input_mask[y * w + rx0..y * w + rx1].iter().copied())
+            .collect();
+
+        let seed = self
+            .click_to_pixel(click_x, click_y)
+            .filter(|&(x, y)| x >= rx0 && x < rx1 && y >= ry0 && y < ry1)
+            .map(|(x, y)| (x - rx0, y - ry0));
+
+        // Run the normal pipeline on a region-sized refiner with these settings
+        // and none of this one's history or stats.
+        let config = Config { output_layout: OutputLayout::RowMajor, ..self.config.clone() };
+        let barrier = if self.barrier.is_empty() { Vec::new() } else { crop(&self.barrier) };
+        let flood_allowed = if self.flood_allowed.is_empty() { Vec::new() } else { crop(&self.flood_allowed) };
+        let mut tile_refiner = self.context(rw, rh, config, barrier, flood_allowed);
+        if !self.paint.is_empty() {
+            tile_refiner.paint = crop(&self.paint);
+        }
+        let refined = tile_refiner.refine_seeded(&region, seed.as_slice(), 1.0);
+        self.status = tile_refiner.status;
+        self.absorb_caches(tile_refiner);
+
+        let (ox, oy) = (tile_x - rx0, tile_y - ry0);
+        let tile = (oy..oy + tile_h)
+            .flat_map(|y| refined[y * rw + ox..y * rw + ox + tile_w].iter().copied())
+            .collect();
+        self.to_output_layout(tile, tile_w, tile_h)
+    }
+
+    /// Stateless variant of `refine_mask` for callers that manage their own
+    /// history (e.g. pipelines refining frames in parallel). `prev_mask` should
+    /// be the previous result of this call: it is averaged with `input_mask`
+    /// for one step of temporal smoothing, and when the click is out of bounds
+    /// the selection follows whatever overlaps it instead of keeping every
+    /// component. Pass an all-zero `prev_mask` for the first frame; it is then
+    /// not averaged in. Fade-out and the other per-frame state are not updated.
+    /// If either length is not `width * height`, returns what `set_on_mismatch`
+    /// selects.
+    pub fn refine_with_prev(&self, input_mask: &[f32], prev_mask: &[f32], click_x: f32, click_y: f32) -> Vec<f32> {
+        let size = self.width * self.height;
+        if input_mask.len() != size || prev_mask.len() != size {
+            return self.mismatch_output(input_mask);
+        }
+
+        let prepared = self.prepare_input(input_mask);
+        let input_mask = prepared.as_deref().unwrap_or(input_mask);
+
+        let mut averaged_mask: Vec<f32> = if prev_mask.iter().all(|&v| v == 0.0) {
+            input_mask.to_vec()
+        } else {
+            input_mask.iter().zip(prev_mask).map(|(a, b)| (a + b) / 2.0).collect()
+        };
+        self.deghost_average(&mut averaged_mask, input_mask);
+        self.remap_alpha(&mut averaged_mask);
+        let anchor: Vec<u8> = prev_mask.iter().map(|&v| (v > self.config.threshold) as u8).collect();
+        let seed = self.click_to_pixel(click_x, click_y);
+        let mut final_mask = self.process(input_mask, averaged_mask, seed.as_slice(), Some(&anchor)).final_mask;
+        self.fill_small_holes(&mut final_mask);
+        final_mask
+    }
+
+    /// A refiner of the given size with `config`, flood constraints already
+    /// mapped to that size, and this refiner's kernel and feather caches (lent
+    /// until `absorb_caches`), for running the pipeline on a cropped, rescaled
+    /// or padded copy of the frame without touching this refiner's state.
+    fn context(&self, width: usize, height: usize, config: Config, barrier: Vec<u8>, flood_allowed: Vec<u8>) -> SubjectRefiner {
+        let mut ctx = SubjectRefiner::new(width, height, self.max_history);
+        ctx.config = config;
+        ctx.barrier = barrier;
+        ctx.flood_allowed = flood_allowed;
+        ctx.kernel_cache = RefCell::new(self.kernel_cache.take());
+        ctx.feather_lut = RefCell::new(self.feather_lut.take());
+        ctx
+    }
+
+    /// Take back the caches lent to a `context` refiner, with what it built.
+    fn absorb_caches(&self, ctx: SubjectRefiner) {
+        self.kernel_cache.replace(ctx.kernel_cache.into_inner());
+        self.feather_lut.replace(ctx.feather_lut.into_inner());
+        self.kernel_builds.set(self.kernel_builds.get() + ctx.kernel_builds.get());
+        self.feather_lut_builds.set(self.feather_lut_builds.get() + ctx.feather_lut_builds.get());
+    }
+
+    /// Refine a whole clip in one call. `masks` holds consecutive
+    /// `width * height` frames; the results come back concatenated in the
+    /// same layout. Returns an empty vec if `masks` is not a whole number of frames.
+    pub fn refine_batch(&mut self, masks: &[f32], click_x: f32, click_y: f32) -> Vec<f32> {
+        self.batch_progress = 0;
+        self.refine_batch_chunk(masks, usize::MAX, click_x, click_y)
+    }
+
+    /// Refine up to `max_frames` frames of a clip, resuming where the previous
+    /// call stopped, so a long clip can be spread over several calls with a
+    /// progress bar in between. Pass the same `masks` each time; the returned
+    /// buffer only holds the frames processed by this call and is empty once
+    /// the clip is done. Call `reset_batch` to start a new clip.
+    pub fn refine_batch_chunk(&mut self, masks: &[f32], max_frames: usize, click_x: f32, click_y: f32) -> Vec<f32> {
+        let size = self.width * self.height;
+        if size == 0 || !masks.len().is_multiple_of(size) {
+            return Vec::new();
+        }
+
+        let total = masks.len() / size;
+        let end = total.min(self.batch_progress.saturating_add(max_frames));
+        let mut out = Vec::with_capacity(end.saturating_sub(self.batch_progress) * size);
+        while self.batch_progress < end {
+            let start = self.batch_progress * size;
+            out.extend(self.refine_mask(&masks[start..start + size], click_x, click_y));
+            self.batch_progress += 1;
+        }
+        out
+    }
+
+    /// Number of frames completed by the current batch, i.e. the index of the
+    /// next frame `refine_batch_chunk` will process.
+    pub fn batch_progress(&self) -> usize {
+        self.batch_progress
+    }
+
+    /// Restart batch progress at frame 0.
+    pub fn reset_batch(&mut self) {
+        self.batch_progress = 0;
+    }
+
+    /// Select the object under a click in the last refined frame and write
+    /// `label` into the persistent selection buffer wherever it covers, so
+    /// several selections can be layered and exported separately from JS.
+    /// Overlaps resolve as last write wins. Returns false if no frame has been
+    /// refined yet, the click is out of bounds, or no object was found nearby.
+    pub fn add_selection(&mut self, click_x: f32, click_y: f32, label: u8) -> bool {
+        let size = self.width * self.height;
+        let seed = match self.click_to_pixel(click_x, click_y) {
+            Some(seed) if self.last_eroded.len() == size => seed,
+            _ => return false,
+        };
+
+        let mut component = vec![0u8; size];
+        if self.flood_fill_labeled(&self.last_eroded, &mut component, seed, 1).is_none() {
+            return false;
+        }
+        let dilated = self.dilate(&component, self.config.dilate_radius);
+
+        if self.selection_labels.len() != size {
+            self.selection_labels = vec![0; size];
+        }
+        for (i, &d) in dilated.iter().enumerate() {
+            if d > 0 && self.last_input[i] > 0.1 {
+                self.selection_labels[i] = label;
+            }
+        }
+        true
+    }
+
+    /// The persistent selection buffer: one label per pixel, 0 where nothing is
+    /// selected. Empty until the first `add_selection`.
+    pub fn selection_labels(&self) -> Vec<u8> {
+        self.selection_labels.clone()
+    }
+
+    /// Merge selection labels whose regions come within `max_gap` pixels of
+    /// each other (measured with the current structuring element: a region is
+    /// dilated by `max_gap` and any label it then overlaps joins it), so a
+    /// subject erosion broke into several fragments becomes one label without
+    /// re-running the pipeline. Each merged group takes its lowest label. Too
+    /// large a gap also merges distinct subjects standing close together.
+    pub fn merge_nearby_labels(&mut self, max_gap: usize) {
+        if self.selection_labels.is_empty() || max_gap == 0 {
+            return;
+        }
+        let mut parent: Vec<u8> = (0..=255).collect();
+        fn root(parent: &[u8], mut l: u8) -> u8 {
+            while parent[l as usize] != l {
+                l = parent[l as usize];
+            }
+            l
+        }
+        for label in 1..=255u8 {
+            let region: Vec<u8> = self.selection_labels.iter().map(|&l| (l == label) as u8).collect();
+            if !region.contains(&1) {
+                continue;
+            }
+            let reach = self.dilate(&region, max_gap as i32);
+            for (&d, &other) in reach.iter().zip(&self.selection_labels) {
+                if d != 0 && other != 0 && other != label {
+                    let (a, b) = (root(&parent, label), root(&parent, other));
+                    parent[a.max(b) as usize] = a.min(b);
+                }
+            }
+        }
+        for l in self.selection_labels.iter_mut() {
+            *l = root(&parent, *l);
+        }
+    }
+
+    /// Add a selection layer named `name` and return its id, or the id of the
+    /// existing layer with that name. Layers keep separate temporal history,
+    /// fade-out and result, so several subjects (e.g. "person" and "logo")
+    /// can be tracked by one refiner: `set_active_layer` picks the one the
+    /// refine calls read and write. Layer 0 is the default layer, active from
+    /// the start. Every layer holds up to `max_history` frames plus two more
+    /// frame-sized buffers, so memory grows linearly with the layer count.
+    pub fn create_layer(&mut self, name: &str) -> u32 {
+        if let Some(id) = (1..self.layers.len()).find(|&id| self.layers[id].name == name) {
+            return id as u32;
+        }
+        self.layers.push(Layer { name: name.to_string(), ..Layer::default() });
+        (self.layers.len() - 1) as u32
+    }
+
+    /// Make layer `id` the one the refine calls use; its history is
+    /// restored and the previous layer's set aside. Settings and the
+    /// per-frame statistics are shared by all layers. Returns false (and
+    /// changes nothing) for an unknown id.
+    pub fn set_active_layer(&mut self, id: u32) -> bool {
+        let id = id as usize;
+        if id >= self.layers.len() {
+            return false;
+        }
+        if id != self.active_layer {
+            let slot = &mut self.layers[self.active_layer];
+            slot.history = std::mem::take(&mut self.history);
+            slot.history_weights = std::mem::take(&mut self.history_weights);
+            slot.fade = std::mem::take(&mut self.fade);
+            slot.last_final = std::mem::take(&mut self.last_final);
+            let next = &mut self.layers[id];
+            self.history = std::mem::take(&mut next.history);
+            self.history_weights = std::mem::take(&mut next.history_weights);
+            self.fade = std::mem::take(&mut next.fade);
+            self.last_final = std::mem::take(&mut next.last_final);
+            self.active_layer = id;
+        }
+        true
+    }
+
+    /// The last refined mask of layer `id` (row-major), empty if that layer
+    /// hasn't been refined yet or the id is unknown.
+    pub fn get_layer_mask(&self, id: u32) -> Vec<f32> {
+        let id = id as usize;
+        if id == self.active_layer {
+            self.last_final.clone()
+        } else {
+            self.layers.get(id).map_or_else(Vec::new, |l| l.last_final.clone())
+        }
+    }
+
+    /// Drop all selections made with `add_selection`.
+    pub fn clear_selections(&mut self) {
+        self.selection_labels.clear();
+    }
+
+    /// Bounding box `[x0, y0, x1, y1)` of the non-zero pixels, if any.
+    fn bbox_of(&self, img: &[u8]) -> Option<[usize; 4]> {
+        let w = self.width;
+        let mut bbox: Option<[usize; 4]> = None;
+        for (i, _) in img.iter().enumerate().filter(|(_, &v)| v != 0) {
+            let (x, y) = (i % w, i / w);
+            let b = bbox.get_or_insert([x, y, x + 1, y + 1]);
+            b[0] = b[0].min(x);
+            b[1] = b[1].min(y);
+            b[2] = b[2].max(x + 1);
+            b[3] = b[3].max(y + 1);
+        }
+        bbox
+    }
+
+    /// The result for a wrong-length input under the `set_on_mismatch` mode.
+    fn mismatch_output(&self, input_mask: &[f32]) -> Vec<f32> {
+        match self.config.on_mismatch {
+            MismatchMode::ReturnInput => input_mask.to_vec(),
+            MismatchMode::ReturnEmpty | MismatchMode::Flag => vec![0.0; self.width * self.height],
+        }
+    }
+
+    /// `mismatch_output`, recording the mismatch in the status if asked to.
+    fn size_mismatch(&mut self, input_mask: &[f32]) -> Vec<f32> {
+        self.status = if self.config.on_mismatch == MismatchMode::Flag { STATUS_SIZE_MISMATCH } else { 0 };
+        self.mismatch_output(input_mask)
+    }
+
+    /// Convert a normalized click to a pixel, or `None` if it is out of bounds.
+    fn click_to_pixel(&self, click_x: f32, click_y: f32) -> Option<(usize, usize)> {
+        let clx = (click_x * self.width as f32) as usize;
+        let cly = (click_y * self.height as f32) as usize;
+        (clx < self.width && cly < self.height).then_some((clx, cly))
+    }
+
+    /// Apply the configured per-frame input filters, or `None` if there are none.
+    fn prepare_input(&self, input_mask: &[f32]) -> Option<Vec<f32>> {
+        let mut out = None;
+        if self.config.sanitize_input {
+            out = Some(self.sanitize(input_mask));
+        }
+        if self.config.deinterlace {
+            let w = self.width;
+            let mut frame = out.unwrap_or_else(|| input_mask.to_vec());
+            for y in (1..self.height).step_by(2) {
+                for x in 0..w {
+                    let above = frame[(y - 1) * w + x];
+                    frame[y * w + x] = if y + 1 < self.height { (above + frame[(y + 1) * w + x]) / 2.0 } else { above };
+                }
+            }
+            out = Some(frame);
+        }
+        if self.config.binary_input {
+            out = Some(out.as_deref().unwrap_or(input_mask).iter().map(|&v| if v >= 0.5 { 1.0 } else { 0.0 }).collect());
+        }
+        if self.config.spatial_median {
+            out = Some(self.median3(out.as_deref().unwrap_or(input_mask)));
+        }
+        out
+    }
+
+    /// Record area, bounding box and border contact of a final mask.
+    fn update_selection_stats(&mut self, final_mask: &[f32]) {
+        let w = self.width;
+        let mut area = 0;
+        let mut bbox = [usize::MAX, usize::MAX, 0, 0];
+        let (mut sx, mut sy) = (0.0f64, 0.0f64);
+        let mut profiles = vec![0u32; self.height + w];
+        for (i, &v) in final_mask.iter().enumerate() {
+            if v > 0.0 {
+                let (x, y) = (i % w, i / w);
+                area += 1;
+                profiles[y] += 1;
+                profiles[self.height + x] += 1;
+                sx += x as f64 + 0.5;
+                sy += y as f64 + 0.5;
+                bbox[0] = bbox[0].min(x);
+                bbox[1] = bbox[1].min(y);
+                bbox[2] = bbox[2].max(x + 1);
+                bbox[3] = bbox[3].max(y + 1);
+            }
+        }
+
+        let centroid = (area > 0).then(|| {
+            ((sx / area as f64) as f32 / self.width as f32, (sy / area as f64) as f32 / self.height as f32)
+        });
+        self.velocity = match (self.last_centroid, centroid) {
+            (Some((px, py)), Some((cx, cy))) => [cx - px, cy - py],
+            _ => [0.0; 2],
+        };
+        let diagonal = |[x0, y0, x1, y1]: [usize; 4]| ((x1 - x0) as f32).hypot((y1 - y0) as f32);
+        self.scale_delta = match (self.last_centroid, centroid) {
+            (Some(_), Some(_)) if self.last_area > 0 => diagonal(bbox) / diagonal(self.last_bbox),
+            _ => 1.0,
+        };
+        self.last_centroid = centroid;
+        match centroid {
+            Some(c) => {
+                if self.centroid_track.len() >= self.max_history.max(2) {
+                    let excess = self.centroid_track.len() + 1 - self.max_history.max(2);
+                    self.centroid_track.drain(..excess);
+                }
+                self.centroid_track.push(c);
+            }
+            None => self.centroid_track.clear(),
+        }
+        self.churn_area = if self.last_final.len() == final_mask.len() {
+            self.last_final.iter().zip(final_mask).filter(|(&a, &b)| (a > 0.0) != (b > 0.0)).count() as u32
+        } else {
+            0
+        };
+        if self.last_final.len() == final_mask.len() {
+            if self.flicker.len() == STABILITY_WINDOW {
+                self.flicker.remove(0);
+            }
+            self.flicker.push(self.churn_area as f32 / self.last_area.max(area).max(1) as f32);
+        }
+
+        self.last_area = area;
+        self.last_bbox = if area > 0 { bbox } else { [0; 4] };
+        let raw = bbox.map(|v| v as f32);
+        let k = self.config.bbox_smoothing;
+        self.smoothed_bbox = match self.smoothed_bbox {
+            _ if area == 0 => None,
+            Some(prev) => Some(std::array::from_fn(|i| k * prev[i] + (1.0 - k) * raw[i])),
+            None => Some(raw),
+        };
+        self.last_profiles = profiles;
+        self.touches_border = area > 0
+            && (bbox[0] == 0 || bbox[1] == 0 || bbox[2] == self.width || bbox[3] == self.height);
+    }
+
+    /// `process_at_scale` confined to the auto-ROI: the inputs, seeds, anchor
+    /// and flood barriers are cropped and processed on an ROI-sized `context`,
+    /// then the stages are pasted back into full-frame buffers. Falls back to
+    /// the full frame without an ROI, for a click outside it, and when the
+    /// cropped result is empty or reaches an ROI edge inside the frame.
+    fn process_in_roi(&self, input_mask: &[f32], averaged_mask: Vec<f32>, seeds: &[(usize, usize)], anchor: Option<&[u8]>) -> Stages {
+        let roi = match self.roi {
+            Some(roi) if self.config.auto_roi && self.config.border_mode != BorderMode::Wrap => roi,
+            _ => return self.process_at_scale(input_mask, averaged_mask, seeds, anchor),
+        };
+        let [x0, y0, x1, y1] = roi;
+        if seeds.iter().any(|&(x, y)| x < x0 || y < y0 || x >= x1 || y >= y1) {
+            return self.process_at_scale(input_mask, averaged_mask, seeds, anchor);
+        }
+        let (w, h) = (self.width, self.height);
+        let (rw, rh) = (x1 - x0, y1 - y0);
+        fn crop<T: Copy>(buf: &[T], w: usize, [x0, y0, x1, y1]: [usize; 4]) -> Vec<T> {
+            (y0..y1).flat_map(|y| buf[y * w + x0..y * w + x1].iter().copied()).collect()
+        }
+
+        let local_seeds: Vec<(usize, usize)> = seeds.iter().map(|&(x, y)| (x - x0, y - y0)).collect();
+        let local_anchor = anchor.map(|a| crop(a, w, roi));
+        let local_barrier = if self.barrier.is_empty() { Vec::new() } else { crop(&self.barrier, w, roi) };
+        let local_allowed = if self.flood_allowed.is_empty() { Vec::new() } else { crop(&self.flood_allowed, w, roi) };
+        let ctx = self.context(rw, rh, self.config.clone(), local_barrier, local_allowed);
+        let local = ctx.process_at_scale(
+            &crop(input_mask, w, roi),
+            crop(&averaged_mask, w, roi),
+            &local_seeds,
+            local_anchor.as_deref(),
+        );
+        let region: Vec<u8> = local.final_mask.iter().map(|&v| (v > 0.0) as u8).collect();
+        let local_bbox = ctx.bbox_of(&region);
+        self.absorb_caches(ctx);
+
+        let escaped = match local_bbox {
+            Some([bx0, by0, bx1, by1]) => {
+                (bx0 == 0 && x0 > 0) || (by0 == 0 && y0 > 0) || (bx1 == rw && x1 < w) || (by1 == rh && y1 < h)
+            }
+            None => true,
+        };
+        if escaped {
+            return self.process_at_scale(input_mask, averaged_mask, seeds, anchor);
+        }
+
+        fn paste<T: Copy + Default>(local: &[T], w: usize, h: usize, [x0, y0, x1, y1]: [usize; 4]) -> Vec<T> {
+            let mut out = vec![T::default(); w * h];
+            let rw = x1 - x0;
+            for y in y0..y1 {
+                out[y * w + x0..y * w + x1].copy_from_slice(&local[(y - y0) * rw..(y - y0 + 1) * rw]);
+            }
+            out
+        }
+        Stages {
+            binary: paste(&local.binary, w, h, roi),
+            eroded: paste(&local.eroded, w, h, roi),
+            final_mask: paste(&local.final_mask, w, h, roi),
+            components: local.components,
+            seed: local.seed.map(|(x, y)| (x + x0, y + y0)),
+            isolated: if local.isolated.is_empty() { Vec::new() } else { paste(&local.isolated, w, h, roi) },
+            gain: local.gain,
+            relax: local.relax,
+            dilate: local.dilate,
+        }
+    }
+
+    /// `process` at the configured process scale: the inputs, seeds, anchor and
+    /// barrier are downscaled and processed on a low-resolution `context` with
+    /// the radii scaled to match, then the isolated region is upscaled and the
+    /// soft values re-applied at full resolution.
+    fn process_at_scale(&self, input_mask: &[f32], averaged_mask: Vec<f32>, seeds: &[(usize, usize)], anchor: Option<&[u8]>) -> Stages {
+        let s = self.config.process_scale;
+        if s <= 1 {
+            return self.process_padded(input_mask, averaged_mask, seeds, anchor);
+        }
+        let (w, h) = (self.width, self.height);
+        let (lw, lh) = (w.div_ceil(s), h.div_ceil(s));
+        let down = |buf: &[f32], max_pool: bool| -> Vec<f32> {
+            let mut out = vec![0.0f32; lw * lh];
+            let mut counts = vec![0u32; lw * lh];
+            for (i, &v) in buf.iter().enumerate() {
+                let cell = (i / w / s) * lw + (i % w) / s;
+                out[cell] = if max_pool { out[cell].max(v) } else { out[cell] + v };
+                counts[cell] += 1;
+            }
+            if !max_pool {
+                for (o, &n) in out.iter_mut().zip(&counts) {
+                    *o /= n as f32;
+                }
+            }
+            out
+        };
+        let down_u8 = |buf: &[u8]| -> Vec<u8> {
+            let f: Vec<f32> = buf.iter().map(|&v| v as f32).collect();
+            down(&f, true).iter().map(|&v| (v > 0.0) as u8).collect()
+        };
+        let up = |low: &[u8]| -> Vec<u8> { (0..w * h).map(|i| low[(i / w / s) * lw + (i % w) / s]).collect() };
+
+        let low_input = down(input_mask, self.config.downscale_max);
+        let low_averaged = down(&averaged_mask, self.config.downscale_max);
+        let low_seeds: Vec<(usize, usize)> = seeds.iter().map(|&(x, y)| (x / s, y / s)).collect();
+        let low_anchor = anchor.map(down_u8);
+        let low_barrier = if self.barrier.is_empty() { Vec::new() } else { down_u8(&self.barrier) };
+        let low_allowed = if self.flood_allowed.is_empty() { Vec::new() } else { down_u8(&self.flood_allowed) };
+        let scaled = |r: i32| (r as f32 / s as f32).round() as i32;
+        let config = Config {
+            erode_radius: scaled(self.config.erode_radius),
+            dilate_radius: scaled(self.config.dilate_radius),
+            protect_click_radius: self.config.protect_click_radius.div_ceil(s),
+            geodesic_limit: self.config.geodesic_limit.map(|d| d / s as f32),
+            max_neck: self.config.max_neck / s as f32,
+            edge_padding: self.config.edge_padding.div_ceil(s),
+            max_selection_area: self.config.max_selection_area.div_ceil((s * s) as u32),
+            // Feathering would grow the low-resolution region; it runs after upscaling
+            erosion_guided_feather: false,
+            // The confidence floor is applied at full resolution, within the isolated box
+            keep_isolated: self.config.keep_isolated || self.config.confidence_floor <= 1.0,
+            ..self.config.clone()
+        };
+        let ctx = self.context(lw, lh, config, low_barrier, low_allowed);
+        let low = ctx.process_padded(&low_input, low_averaged, &low_seeds, low_anchor.as_deref());
+        self.absorb_caches(ctx);
+
+        let region: Vec<u8> = low.final_mask.iter().map(|&v| (v > 0.0) as u8).collect();
+        let centre = |x: usize, y: usize| ((x * s + s / 2).min(w - 1), (y * s + s / 2).min(h - 1));
+        let (binary, eroded) = (up(&low.binary), up(&low.eroded));
+        let isolated = if low.isolated.is_empty() { Vec::new() } else { up(&low.isolated) };
+        let mut final_mask = self.reapply(input_mask, &up(&region), &isolated);
+        self.guided_feather(&binary, &eroded, &mut final_mask);
+        Stages {
+            binary,
+            eroded,
+            final_mask,
+            components: low.components,
+            seed: low.seed.map(|(x, y)| centre(x, y)),
+            isolated,
+            gain: low.gain,
+            relax: low.relax,
+            dilate: low.dilate * s as i32,
+        }
+    }
+
+    /// `process` on the frame padded by `edge_padding` replicated pixels per
+    /// side: the inputs, seeds, anchor and barrier are padded, processed on a
+    /// padded-size `context`, and the stages cropped back.
+    fn process_padded(&self, input_mask: &[f32], averaged_mask: Vec<f32>, seeds: &[(usize, usize)], anchor: Option<&[u8]>) -> Stages {
+        let p = self.config.edge_padding;
+        let (w, h) = (self.width, self.height);
+        if p == 0 || w == 0 || h == 0 || self.config.border_mode == BorderMode::Wrap {
+            return self.process(input_mask, averaged_mask, seeds, anchor);
+        }
+        let (pw, ph) = (w + 2 * p, h + 2 * p);
+        fn pad<T: Copy>(buf: &[T], w: usize, h: usize, p: usize) -> Vec<T> {
+            let pw = w + 2 * p;
+            (0..(h + 2 * p) * pw)
+                .map(|i| {
+                    let x = (i % pw).saturating_sub(p).min(w - 1);
+                    let y = (i / pw).saturating_sub(p).min(h - 1);
+                    buf[y * w + x]
+                })
+                .collect()
+        }
+        fn crop<T: Copy>(buf: &[T], w: usize, h: usize, p: usize) -> Vec<T> {
+            let pw = w + 2 * p;
+            (p..p + h).flat_map(|y| buf[y * pw + p..y * pw + p + w].iter().copied()).collect()
+        }
+
+        let padded_seeds: Vec<(usize, usize)> = seeds.iter().map(|&(x, y)| (x + p, y + p)).collect();
+        let padded_anchor = anchor.map(|a| pad(a, w, h, p));
+        let padded_barrier = if self.barrier.is_empty() { Vec::new() } else { pad(&self.barrier, w, h, p) };
+        let padded_allowed = if self.flood_allowed.is_empty() { Vec::new() } else { pad(&self.flood_allowed, w, h, p) };
+        let ctx = self.context(pw, ph, self.config.clone(), padded_barrier, padded_allowed);
+        let padded = ctx.process(&pad(input_mask, w, h, p), pad(&averaged_mask, w, h, p), &padded_seeds, padded_anchor.as_deref());
+        self.absorb_caches(ctx);
+        let clamp = |v: usize, n: usize| v.saturating_sub(p).min(n - 1);
+        Stages {
+            binary: crop(&padded.binary, w, h, p),
+            eroded: crop(&padded.eroded, w, h, p),
+            final_mask: crop(&padded.final_mask, w, h, p),
+            components: padded.components,
+            seed: padded.seed.map(|(x, y)| (clamp(x, w), clamp(y, h))),
+            isolated: if padded.isolated.is_empty() { Vec::new() } else { crop(&padded.isolated, w, h, p) },
+            gain: padded.gain,
+            relax: padded.relax,
+            dilate: padded.dilate,
+        }
+    }
+
+    /// Threshold, erode, isolate, dilate and re-apply soft values for one frame
+    /// whose temporal average has already been computed. Holds no state, so
+    /// both the stateful and stateless entry points share it. The components
+    /// under `seeds` are kept; with no seeds (clicks out of bounds) and an
+    /// `anchor` that has foreground, the components overlapping it are kept
+    /// instead of everything.
+    fn process(&self, input_mask: &[f32], mut averaged_mask: Vec<f32>, seeds: &[(usize, usize)], anchor: Option<&[u8]>) -> Stages {
+        let size = self.width * self.height;
+
+        let gain = if self.config.auto_gain { Self::apply_auto_gain(&mut averaged_mask) } else { 1.0 };
+
+        // 2. Thresholding and Erosion
+        // We erode to break "bridges" between touching objects
+        let binary = self.threshold_mask(&averaged_mask);
+        let mut eroded = if self.config.erosion_enabled && self.config.max_neck > 0.0 {
+            let half = self.config.max_neck / 2.0;
+            let core: Vec<u8> = self.distance_transform(&binary, true).iter().map(|&d| (d > half) as u8).collect();
+            let wide = self.dilate(&core, half.ceil() as i32);
+            binary.iter().zip(wide).map(|(&b, w)| b & w).collect()
+        } else if self.config.erosion_enabled {
+            self.erode(&binary, self.config.erode_radius)
+        } else {
+            binary.clone()
+        };
+        if self.config.preserve_thin && self.config.erosion_enabled {
+            for (e, s) in eroded.iter_mut().zip(self.skeleton(&binary)) {
+                *e |= s;
+            }
+        }
+        if self.config.protect_click_radius > 0 && self.config.erosion_enabled {
+            let r = self.config.protect_click_radius as i32;
+            let (w, h) = (self.width as i32, self.height as i32);
+            for &(sx, sy) in seeds {
+                for dy in -r..=r {
+                    for dx in -r..=r {
+                        let ny = sy as i32 + dy;
+                        if dx * dx + dy * dy > r * r || ny < 0 || ny >= h {
+                            continue;
+                        }
+                        if let Some(nx) = self.wrap_x(sx as i32 + dx) {
+                            let idx = (ny * w + nx) as usize;
+                            eroded[idx] |= binary[idx];
+                        }
+                    }
+                }
+            }
+        }
+
+        if self.config.robust_seed {
+            let confident: Vec<u8> = input_mask.iter().map(|&v| (v >= ROBUST_SEED_CONFIDENCE) as u8).collect();
+            let mut rescued = vec![0u8; size];
+            for &(sx, sy) in seeds {
+                let idx = sy * self.width + sx;
+                if !self.fillable(&eroded, idx) && confident[idx] == 1 {
+                    self.flood_fill(&confident, &mut rescued, sx, sy);
+                }
+            }
+            for (e, r) in eroded.iter_mut().zip(rescued) {
+                *e |= r;
+            }
+        }
+
+        let mut relax = 1.0f32;
+        if self.config.click_threshold_relax {
+            for &(sx, sy) in seeds {
+                if self.seedable_near(&eroded, sx, sy) {
+                    continue;
+                }
+                for factor in [0.75, 0.5, 0.25] {
+                    let relaxed: Vec<u8> = averaged_mask.iter().map(|&v| (v > self.config.threshold * factor) as u8).collect();
+                    let relaxed = if self.config.erosion_enabled { self.erode(&relaxed, self.config.erode_radius) } else { relaxed };
+                    let mut component = vec![0u8; size];
+                    if self.flood_fill(&relaxed, &mut component, sx, sy).is_some() {
+                        for (e, c) in eroded.iter_mut().zip(component) {
+                            *e |= c;
+                        }
+                        relax = relax.min(factor);
+                        break;
+                    }
+                }
+            }
+        }
+
+        // 3. Flood Fill (Connected Component) to isolate the clicked object
+        let mut isolated = vec![0u8; size];
+        let tolerant: Vec<u8>;
+        let fill_on: &[u8] = match self.config.flood_source {
+            FloodSource::Eroded => &eroded,
+            FloodSource::Binary => &binary,
+            FloodSource::Soft => {
+                tolerant = averaged_mask.iter().map(|&v| (v > self.config.threshold / 2.0) as u8).collect();
+                &tolerant
+            }
+        };
+
+        let mut components = 0;
+        let mut filled = 0;
+        let mut resolved = None;
+        let mut starts = Vec::new();
+
+        if !seeds.is_empty() {
+            for &(clx, cly) in seeds {
+                // Already inside a component an earlier seed filled
+                if isolated[cly * self.width + clx] != 0 {
+                    resolved = Some((clx, cly));
+                    continue;
+                }
+                resolved = self.pyramid_fill(fill_on, &mut isolated, clx, cly);
+                starts.extend(resolved);
+                // A seed snapped to a nearby pixel may still reach an old component
+                let now = isolated.iter().filter(|&&v| v != 0).count();
+                if now > filled {
+                    components += 1;
+                    filled = now;
+                }
+            }
+        } else {
+            match anchor {
+                // Follow whatever overlaps the previous selection
+                Some(prev) if prev.iter().any(|&v| v != 0) => {
+                    let marker: Vec<u8> = prev.iter().zip(fill_on).map(|(&p, &e)| p & e).collect();
+                    isolated = self.reconstruct(&marker, fill_on);
+                }
+                // If click is out of bounds, fallback to full eroded
+                _ => isolated = fill_on.to_vec(),
+            }
+        }
+
+        if self.config.max_selection_area > 0 && filled > self.config.max_selection_area as usize {
+            self.limit_area(&mut isolated, &starts, self.config.max_selection_area as usize);
+        }
+
+        let kept = if self.config.keep_isolated || self.config.debug_layers { isolated.clone() } else { Vec::new() };
+        let radius = if self.config.dilate_after_isolate && self.config.erosion_enabled { self.capped_dilate_radius(&isolated) } else { 0 };
+        let mut final_mask = if self.config.grayscale_dilate && self.config.dilate_after_isolate && self.config.erosion_enabled {
+            // 4-5. Grow the soft values themselves in one pass
+            let soft = self.reapply(input_mask, &isolated, &isolated);
+            self.dilate_f32(&soft, radius)
+        } else if self.config.aa_isolate {
+            let coverage = self.edge_coverage(&isolated, &averaged_mask);
+            let coverage = if self.config.dilate_after_isolate && self.config.erosion_enabled {
+                self.dilate_f32(&coverage, radius)
+            } else {
+                coverage
+            };
+            let region: Vec<u8> = coverage.iter().map(|&c| (c > 0.0) as u8).collect();
+            let mut soft = self.reapply(input_mask, &region, &isolated);
+            for (v, c) in soft.iter_mut().zip(coverage) {
+                *v *= c;
+            }
+            soft
+        } else {
+            // 4. Dilation to restore edges
+            let dilated = if self.config.dilate_after_isolate && self.config.erosion_enabled && self.config.gradient_dilate {
+                self.gradient_dilate(&isolated, input_mask, radius)
+            } else if self.config.dilate_after_isolate && self.config.erosion_enabled {
+                self.dilate(&isolated, radius)
+            } else {
+                isolated.clone()
+            };
+            let dilated = if self.config.edge_smooth && !self.config.hard_output { self.majority_smooth(&dilated, 2) } else { dilated };
+
+            // 5. Re-apply original confidence values to the isolated blob
+            self.reapply(input_mask, &dilated, &isolated)
+        };
+        self.guided_feather(&binary, &eroded, &mut final_mask);
+
+        Stages { binary, eroded, final_mask, components, seed: resolved, isolated: kept, gain, relax, dilate: radius }
+    }
+
+    /// The dilate radius, reduced under `set_max_growth` to the largest one
+    /// whose dilation of `isolated` stays within the allowed area (binary
+    /// search, as the dilated area only grows with the radius).
+    fn capped_dilate_radius(&self, isolated: &[u8]) -> i32 {
+        let radius = self.config.dilate_radius.max(0);
+        if self.config.max_growth <= 0.0 || radius == 0 {
+            return radius;
+        }
+        let limit = self.config.max_growth * isolated.iter().filter(|&&v| v != 0).count() as f32;
+        let fits = |r: i32| self.dilate(isolated, r).iter().filter(|&&v| v != 0).count() as f32 <= limit;
+        if fits(radius) {
+            return radius;
+        }
+        let (mut lo, mut hi) = (0, radius);
+        while hi - lo > 1 {
+            let mid = (lo + hi) / 2;
+            if fits(mid) {
+                lo = mid;
+            } else {
+                hi = mid;
+            }
+        }
+        lo
+    }
+
+    /// Box-blur `final_mask` with a per-pixel radius set by how much of its
+    /// neighbourhood erosion removed for good (`set_erosion_guided_feather`).
+    fn guided_feather(&self, binary: &[u8], eroded: &[u8], final_mask: &mut [f32]) {
+        let r = self.config.erode_radius;
+        if !self.config.erosion_guided_feather || !self.config.erosion_enabled || self.config.hard_output || r <= 0 {
+            return;
+        }
+        let (w, h) = (self.width, self.height);
+        let opened = self.dilate(eroded, r);
+        let lost: Vec<f32> = binary.iter().zip(&opened).map(|(&b, &o)| (b != 0 && o == 0) as u8 as f32).collect();
+        // Summed-area tables, one row and column of zeros in front
+        let table = |img: &[f32]| -> Vec<f32> {
+            let mut t = vec![0.0f32; (w + 1) * (h + 1)];
+            for y in 0..h {
+                for x in 0..w {
+                    t[(y + 1) * (w + 1) + x + 1] =
+                        img[y * w + x] + t[y * (w + 1) + x + 1] + t[(y + 1) * (w + 1) + x] - t[y * (w + 1) + x];
+                }
+            }
+            t
+        };
+        let box_mean = |t: &[f32], x: usize, y: usize, r: usize| -> f32 {
+            let (x0, y0) = (x.saturating_sub(r), y.saturating_sub(r));
+            let (x1, y1) = ((x + r + 1).min(w), (y + r + 1).min(h));
+            let sum = t[y1 * (w + 1) + x1] - t[y0 * (w + 1) + x1] - t[y1 * (w + 1) + x0] + t[y0 * (w + 1) + x0];
+            sum / ((x1 - x0) * (y1 - y0)) as f32
+        };
+        let solid: Vec<f32> = binary.iter().map(|&b| (b != 0) as u8 as f32).collect();
+        let (lost_table, solid_table) = (table(&lost), table(&solid));
+        let mask_table = table(final_mask);
+        for (i, v) in final_mask.iter_mut().enumerate() {
+            let (x, y) = (i % w, i / w);
+            let present = box_mean(&solid_table, x, y, r as usize);
+            if present <= 0.0 {
+                continue;
+            }
+            let radius = (box_mean(&lost_table, x, y, r as usize) / present * r as f32).round() as usize;
+            if radius > 0 {
+                *v = box_mean(&mask_table, x, y, radius);
+            }
+        }
+    }
+
+    /// Copy the soft input values inside `dilated` (plus pixels forced in by the
+    /// confidence floor), applying the binary-output and gamma settings.
+    fn reapply(&self, input_mask: &[f32], dilated: &[u8], isolated: &[u8]) -> Vec<f32> {
+        let size = self.width * self.height;
+        let floor_box = if self.config.confidence_floor <= 1.0 { self.bbox_of(isolated) } else { None };
+        let forced = |i: usize| {
+            floor_box.is_some_and(|[x0, y0, x1, y1]| {
+                let (x, y) = (i % self.width, i / self.width);
+                input_mask[i] >= self.config.confidence_floor && x >= x0 && x < x1 && y >= y0 && y < y1
+            })
+        };
+        let mut final_mask = vec![0.0; size];
+        for i in 0..size {
+            if self.config.hard_output {
+                final_mask[i] = (dilated[i] > 0 || forced(i)) as u8 as f32;
+            } else if (dilated[i] > 0 || forced(i)) && input_mask[i] > 0.1 {
+                // Keep the smooth edges of the original AI mask, but only within our isolated zone
+                final_mask[i] = input_mask[i];
+                if self.config.binary_input {
+                    final_mask[i] = 1.0;
+                } else {
+                    final_mask[i] = self.alpha_curve_at(final_mask[i]);
+                    if self.config.alpha_gamma != 1.0 {
+                        final_mask[i] = final_mask[i].clamp(0.0, 1.0).powf(self.config.alpha_gamma);
+                    }
+                }
+            }
+        }
+        final_mask
+    }
+
+    /// Reorder a row-major `w * h` mask into the configured output layout,
+    /// applying the background floor.
+    fn to_output_layout(&self, mut mask: Vec<f32>, w: usize, h: usize) -> Vec<f32> {
+        if let Some(floor) = self.config.background_floor {
+            let cutoff = floor.max(BACKGROUND_FLOOR_CUTOFF);
+            for v in mask.iter_mut().filter(|v| **v <= cutoff) {
+                *v = floor;
+            }
+        }
+        match self.config.output_layout {
+            OutputLayout::RowMajor => mask,
+            OutputLayout::ColumnMajor => (0..w * h).map(|i| mask[(i % h) * w + i / h]).collect(),
+        }
+    }
+
+    /// `v` through the alpha curve (`set_alpha_curve`); `v` itself without one.
+    fn alpha_curve_at(&self, v: f32) -> f32 {
+        let lut = &self.config.alpha_curve;
+        if lut.is_empty() {
+            return v;
+        }
+        let t = v.clamp(0.0, 1.0) * (lut.len() - 1) as f32;
+        let i = (t as usize).min(lut.len() - 2);
+        let f = t - i as f32;
+        lut[i] * (1.0 - f) + lut[i + 1] * f
+    }
+
+    fn remap_alpha(&self, averaged: &mut [f32]) {
+        if self.config.alpha_curve.is_empty() {
+            return;
+        }
+        for v in averaged.iter_mut() {
+            *v = self.alpha_curve_at(*v);
+        }
+    }
+
+    fn deghost_average(&self, averaged: &mut [f32], latest: &[f32]) {
+        if self.config.deghost <= 0.0 {
+            return;
+        }
+        for (a, &l) in averaged.iter_mut().zip(latest) {
+            if l <= 0.1 {
+                *a -= self.config.deghost * (*a - l);
+            }
+        }
+    }
+
+    /// History frame `k` for the temporal average, aligned to `current` under
+    /// `set_logpolar_smoothing` or `set_block_match_align` unless it is the
+    /// newest frame.
+    fn history_frame(&self, k: usize, current: &[f32]) -> Cow<'_, [f32]> {
+        let frame = self.history[k].decode();
+        if k + 1 == self.history.len() {
+            return frame;
+        }
+        if self.config.logpolar_smoothing {
+            if let Some((cx, cy)) = self.last_centroid {
+                let centre = (cx * self.width as f32 + 0.5, cy * self.height as f32 + 0.5);
+                return Cow::Owned(self.logpolar_align(&frame, current, centre));
+            }
+        }
+        let (block, search) = self.config.block_match;
+        if block == 0 || search == 0 {
+            return frame;
+        }
+        Cow::Owned(self.align_to(&frame, current, block, search as i32))
+    }
+
+    /// `frame` rotated and scaled about `centre` by the log-polar shift that
+    /// best matches `current` (`set_logpolar_smoothing`). Samples past the
+    /// frame edge are background.
+    fn logpolar_align(&self, frame: &[f32], current: &[f32], (cx, cy): (f32, f32)) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        let (na, nr) = (LOGPOLAR_ANGLES, LOGPOLAR_RADII);
+        let r_max = [(0.0, 0.0), (w as f32, 0.0), (0.0, h as f32), (w as f32, h as f32)]
+            .iter()
+            .map(|&(x, y): &(f32, f32)| (x - cx).hypot(y - cy))
+            .fold(1.0f32, f32::max);
+        let step = r_max.ln_1p() / (nr - 1) as f32;
+        let d_theta = std::f32::consts::TAU / na as f32;
+        // Bilinear value at a point, with pixel centres at +0.5
+        let sample = |m: &[f32], fx: f32, fy: f32| {
+            let (gx, gy) = (fx - 0.5, fy - 0.5);
+            let (x0, y0) = (gx.floor() as i32, gy.floor() as i32);
+            let (tx, ty) = (gx - x0 as f32, gy - y0 as f32);
+            let at = |x: i32, y: i32| {
+                if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 { 0.0 } else { m[y as usize * w + x as usize] }
+            };
+            let top = at(x0, y0) * (1.0 - tx) + at(x0 + 1, y0) * tx;
+            let bottom = at(x0, y0 + 1) * (1.0 - tx) + at(x0 + 1, y0 + 1) * tx;
+            top * (1.0 - ty) + bottom * ty
+        };
+        // Row j holds radius exp(j * step) - 1, column a angle a * d_theta
+        let to_logpolar = |m: &[f32]| -> Vec<f32> {
+            (0..na * nr)
+                .map(|i| {
+                    let (sin, cos) = ((i % na) as f32 * d_theta).sin_cos();
+                    let r = ((i / na) as f32 * step).exp_m1();
+                    sample(m, cx + r * cos, cy + r * sin)
+                })
+                .collect()
+        };
+        let (older, newest) = (to_logpolar(frame), to_logpolar(current));
+
+        let error = |da: usize, dr: i32| {
+            let rows = (dr.max(0) as usize)..(nr as i32 + dr.min(0)) as usize;
+            let mut sum = 0.0;
+            for j in rows.clone() {
+                let src = (j as i32 - dr) as usize * na;
+                for a in 0..na {
+                    let d = older[src + (a + na - da) % na] - newest[j * na + a];
+                    sum += d * d;
+                }
+            }
+            sum / (rows.len() * na) as f32
+        };
+        let mut best = (0, 0, error(0, 0));
+        for dr in -LOGPOLAR_SCALE_STEPS..=LOGPOLAR_SCALE_STEPS {
+            for da in 0..na {
+                let e = error(da, dr);
+                if e < best.2 {
+                    best = (da, dr, e);
+                }
+            }
+        }
+        let (da, dr) = (best.0, best.1);
+        if (da, dr) == (0, 0) {
+            return frame.to_vec();
+        }
+
+        let rotation = da as f32 * d_theta;
+        let log_shift = dr as f32 * step;
+        (0..w * h)
+            .map(|i| {
+                let (dx, dy) = ((i % w) as f32 + 0.5 - cx, (i / w) as f32 + 0.5 - cy);
+                let r = (dx.hypot(dy).ln_1p() - log_shift).exp_m1().max(0.0);
+                let (sin, cos) = (dy.atan2(dx) - rotation).sin_cos();
+                sample(frame, cx + r * cos, cy + r * sin)
+            })
+            .collect()
+    }
+
+    /// `frame` with each `block`-sized tile on the boundary of `current`
+    /// (thresholded) read from the shift within `search` that best matches
+    /// `current` there (`set_block_match_align`). Samples past the frame edge
+    /// repeat the edge.
+    fn align_to(&self, frame: &[f32], current: &[f32], block: usize, search: i32) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        let binary = self.threshold_mask(current);
+        let on_boundary = |x: usize, y: usize| {
+            let v = binary[y * w + x];
+            (x > 0 && binary[y * w + x - 1] != v)
+                || (x + 1 < w && binary[y * w + x + 1] != v)
+                || (y > 0 && binary[(y - 1) * w + x] != v)
+                || (y + 1 < h && binary[(y + 1) * w + x] != v)
+        };
+        let sample = |x: usize, y: usize, dx: i32, dy: i32| {
+            let sx = (x as i32 + dx).clamp(0, w as i32 - 1) as usize;
+            let sy = (y as i32 + dy).clamp(0, h as i32 - 1) as usize;
+            frame[sy * w + sx]
+        };
+
+        let mut out = frame.to_vec();
+        for by in (0..h).step_by(block) {
+            for bx in (0..w).step_by(block) {
+                let (x1, y1) = ((bx + block).min(w), (by + block).min(h));
+                if !(by..y1).any(|y| (bx..x1).any(|x| on_boundary(x, y))) {
+                    continue;
+                }
+                let sad = |dx: i32, dy: i32| -> f32 {
+                    (by..y1).flat_map(|y| (bx..x1).map(move |x| (x, y))).map(|(x, y)| (current[y * w + x] - sample(x, y, dx, dy)).abs()).sum()
+                };
+                let mut best = (0, 0, sad(0, 0));
+                for dy in -search..=search {
+                    for dx in -search..=search {
+                        let d = sad(dx, dy);
+                        if d < best.2 {
+                            best = (dx, dy, d);
+                        }
+                    }
+                }
+                let area = ((x1 - bx) * (y1 - by)) as f32;
+                if (best.0, best.1) == (0, 0) || best.2 > BLOCK_MATCH_MAX_ERROR * area {
+                    continue;
+                }
+                for y in by..y1 {
+                    for x in bx..x1 {
+                        out[y * w + x] = sample(x, y, best.0, best.1);
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn threshold_mask(&self, mask: &[f32]) -> Vec<u8> {
+        mask.iter().map(|&v| (v > self.config.threshold) as u8).collect()
+    }
+
+    fn erode(&self, img: &[u8], radius: i32) -> Vec<u8> {
+        let mut out = vec![0; img.len()];
+        self.erode_rows(img, &mut out, radius, 0..self.height);
+        out
+    }
+
+    /// Write rows `rows` of the erosion of `img` into `out`.
+    fn erode_rows(&self, img: &[u8], out: &mut [u8], radius: i32, rows: std::ops::Range<usize>) {
+        let offsets = self.kernel_offsets(radius);
+        let w = self.width as i32;
+        let h = self.height as i32;
+
+        for y in rows.start as i32..rows.end as i32 {
+            for x in 0..w {
+                let mut min_val = 1;
+                for &(dx, dy) in offsets.iter() {
+                    let ny = y + dy;
+                    match self.wrap_x(x + dx) {
+                        Some(nx) if ny >= 0 && ny < h => {
+                            let idx = (ny * w + nx) as usize;
+                            if img[idx] == 0 {
+                                min_val = 0;
+                            }
+                        }
+                        _ => min_val = 0,
+                    }
+                }
+                out[(y * w + x) as usize] = min_val;
+            }
+        }
+    }
+
+    fn dilate(&self, img: &[u8], radius: i32) -> Vec<u8> {
+        let mut out = vec![0; img.len()];
+        self.dilate_rows(img, &mut out, radius, 0..self.height);
+        out
+    }
+
+    /// Spread the foreground of rows `rows` of `img` into `out` by the
+    /// element; covering every row in any order gives the full dilation.
+    fn dilate_rows(&self, img: &[u8], out: &mut [u8], radius: i32, rows: std::ops::Range<usize>) {
+        let offsets = self.kernel_offsets(radius);
+        let w = self.width as i32;
+        let h = self.height as i32;
+
+        for y in rows.start as i32..rows.end as i32 {
+            for x in 0..w {
+                if img[(y * w + x) as usize] == 1 {
+                    for &(dx, dy) in offsets.iter() {
+                        let ny = y + dy;
+                        if let Some(nx) = self.wrap_x(x + dx) {
+                            if ny >= 0 && ny < h {
+                                out[(ny * w + nx) as usize] = 1;
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Dilation of `img` by `radius` that only steps where `soft` doesn't drop
+    /// steeply along the step (`set_gradient_dilate`).
+    fn gradient_dilate(&self, img: &[u8], soft: &[f32], radius: i32) -> Vec<u8> {
+        let (w, h) = (self.width as i32, self.height as i32);
+        let reach = self.dilate(img, radius);
+        let at = |x: i32, y: i32| soft[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize];
+        let grad: Vec<(f32, f32)> = (0..w * h)
+            .map(|i| {
+                let (x, y) = (i % w, i / w);
+                ((at(x + 1, y) - at(x - 1, y)) / 2.0, (at(x, y + 1) - at(x, y - 1)) / 2.0)
+            })
+            .collect();
+
+        let mut out = img.to_vec();
+        let mut frontier: Vec<usize> = (0..img.len()).filter(|&i| img[i] != 0).collect();
+        for _ in 0..radius.max(0) {
+            let mut next = Vec::new();
+            for &i in &frontier {
+                let (x, y) = (i as i32 % w, i as i32 / w);
+                for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                    let ny = y + dy;
+                    let Some(nx) = self.wrap_x(x + dx) else { continue };
+                    if ny < 0 || ny >= h {
+                        continue;
+                    }
+                    let n = (ny * w + nx) as usize;
+                    if out[n] != 0 || reach[n] == 0 {
+                        continue;
+                    }
+                    let len = ((dx * dx + dy * dy) as f32).sqrt();
+                    let (gx, gy) = grad[n];
+                    if (gx * dx as f32 + gy * dy as f32) / len < -GRADIENT_DILATE_DROP {
+                        continue;
+                    }
+                    out[n] = 1;
+                    next.push(n);
+                }
+            }
+            frontier = next;
+        }
+        out
+    }
+
+    /// SLIC-style superpixel labels of `src` (RGBA): centres seeded on a
+    /// `cell` grid, then five k-means rounds where each pixel joins the
+    /// closest centre within a `2 * cell` window by RGB distance plus
+    /// compactness-weighted spatial distance. Labels are dense from 0.
+    fn superpixels(&self, src: &[u8], cell: usize) -> Vec<u32> {
+        const COMPACTNESS: f32 = 10.0;
+        let (w, h) = (self.width, self.height);
+        let colour = |i: usize| [src[i * 4] as f32, src[i * 4 + 1] as f32, src[i * 4 + 2] as f32];
+        // Centres as (x, y, r, g, b)
+        let mut centres: Vec<[f32; 5]> = Vec::new();
+        for y in (cell / 2..h).step_by(cell) {
+            for x in (cell / 2..w).step_by(cell) {
+                let [r, g, b] = colour(y * w + x);
+                centres.push([x as f32, y as f32, r, g, b]);
+            }
+        }
+        if centres.is_empty() {
+            return vec![0; w * h];
+        }
+
+        let mut labels = vec![0u32; w * h];
+        let spatial = (COMPACTNESS / cell as f32).powi(2);
+        for _ in 0..5 {
+            let mut best = vec![f32::INFINITY; w * h];
+            for (k, c) in centres.iter().enumerate() {
+                let (cx, cy) = (c[0] as i64, c[1] as i64);
+                let reach = 2 * cell as i64;
+                for y in (cy - reach).max(0)..(cy + reach + 1).min(h as i64) {
+                    for x in (cx - reach).max(0)..(cx + reach + 1).min(w as i64) {
+                        let i = y as usize * w + x as usize;
+                        let p = colour(i);
+                        let dc = (p[0] - c[2]).powi(2) + (p[1] - c[3]).powi(2) + (p[2] - c[4]).powi(2);
+                        let ds = (x as f32 - c[0]).powi(2) + (y as f32 - c[1]).powi(2);
+                        let d = dc + ds * spatial;
+                        if d < best[i] {
+                            best[i] = d;
+                            labels[i] = k as u32;
+                        }
+                    }
+                }
+            }
+            let mut sums = vec![[0.0f32; 6]; centres.len()];
+            for (i, &l) in labels.iter().enumerate() {
+                let [r, g, b] = colour(i);
+                let acc = &mut sums[l as usize];
+                for (a, v) in acc.iter_mut().zip([(i % w) as f32, (i / w) as f32, r, g, b, 1.0]) {
+                    *a += v;
+                }
+            }
+            for (c, acc) in centres.iter_mut().zip(&sums) {
+                if acc[5] > 0.0 {
+                    for (v, a) in c.iter_mut().zip(acc) {
+                        *v = a / acc[5];
+                    }
+                }
+            }
+        }
+        labels
+    }
+
+    /// Coverage of `isolated` with its edge antialiased from `averaged`: 1 on
+    /// interior pixels, the share of 4x4 bilinear samples above the threshold
+    /// on pixels either side of the edge, and 0 elsewhere.
+    fn edge_coverage(&self, isolated: &[u8], averaged: &[f32]) -> Vec<f32> {
+        let (w, h) = (self.width, self.height);
+        let at = |x: i32, y: i32| averaged[y.clamp(0, h as i32 - 1) as usize * w + x.clamp(0, w as i32 - 1) as usize];
+        // Bilinear value at a point, with pixel centres at +0.5
+        let sample = |fx: f32, fy: f32| {
+            let (gx, gy) = (fx - 0.5, fy - 0.5);
+            let (x0, y0) = (gx.floor() as i32, gy.floor() as i32);
+            let (tx, ty) = (gx - x0 as f32, gy - y0 as f32);
+            let top = at(x0, y0) * (1.0 - tx) + at(x0 + 1, y0) * tx;
+            let bottom = at(x0, y0 + 1) * (1.0 - tx) + at(x0 + 1, y0 + 1) * tx;
+            top * (1.0 - ty) + bottom * ty
+        };
+        let mut out: Vec<f32> = isolated.iter().map(|&v| (v != 0) as u8 as f32).collect();
+        for i in 0..isolated.len() {
+            let (x, y) = (i % w, i / w);
+            let inside = isolated[i] != 0;
+            let mut neighbours = Vec::with_capacity(4);
+            if x > 0 { neighbours.push(i - 1); }
+            if x < w - 1 { neighbours.push(i + 1); }
+            if y > 0 { neighbours.push(i - w); }
+            if y < h - 1 { neighbours.push(i + w); }
+            if !neighbours.iter().any(|&n| (isolated[n] != 0) != inside) {
+                continue;
+            }
+            let mut hits = 0;
+            for sy in 0..4 {
+                for sx in 0..4 {
+                    let (fx, fy) = (x as f32 + (sx as f32 + 0.5) / 4.0, y as f32 + (sy as f32 + 0.5) / 4.0);
+                    hits += (sample(fx, fy) > self.config.threshold) as u32;
+                }
+            }
+            out[i] = hits as f32 / 16.0;
+        }
+        out
+    }
+
+    /// Grayscale dilation: each pixel becomes the maximum of `img` over the
+    /// structuring element of `radius` around it.
+    fn dilate_f32(&self, img: &[f32], radius: i32) -> Vec<f32> {
+        let mut out = vec![0.0f32; img.len()];
+        let offsets = self.kernel_offsets(radius);
+        let w = self.width as i32;
+        let h = self.height as i32;
+
+        for y in 0..h {
+            for x in 0..w {
+                let v = img[(y * w + x) as usize];
+                if v <= 0.0 {
+                    continue;
+                }
+                for &(dx, dy) in offsets.iter() {
+                    let ny = y + dy;
+                    if let Some(nx) = self.wrap_x(x + dx) {
+                        if ny >= 0 && ny < h {
+                            let o = &mut out[(ny * w + nx) as usize];
+                            *o = o.max(v);
+                        }
+                    }
+                }
+            }
+        }
+        out
+    }
+
+    fn flood_fill(&self, img: &[u8], out: &mut [u8], start_x: usize, start_y: usize) -> Option<(usize, usize)> {
+        self.flood_fill_labeled(img, out, (start_x, start_y), 1)
+    }
+
+    /// Flood fill the component of `img` at `seed` (or the nearest foreground
+    /// pixel within 20px), writing `label` into `out`. Existing values in `out`
+    /// are overwritten. While a geodesic limit is set, pixels farther than it
+    /// from the start along the fill are left out. Returns the pixel the fill
+    /// started from, or `None` if no seed pixel was found.
+    fn flood_fill_labeled(&self, img: &[u8], out: &mut [u8], seed: (usize, usize), label: u8) -> Option<(usize, usize)> {
+        let (start_x, start_y) = seed;
+        let w = self.width;
+        let h = self.height;
+
+        let start_idx = start_y * w + start_x;
+        
+        // Find nearest 1 if starting point is 0
+        let mut q = std::collections::VecDeque::new();
+        
+        if self.fillable(img, start_idx) {
+            q.push_back((start_x, start_y, 0));
+        } else {
+            // Search nearby for a 1
+            let mut found = false;
+            for r in 1..=SEED_SEARCH_RADIUS {
+                for dy in -r..=r {
+                    for dx in -r..=r {
+                        let ny = start_y as i32 + dy;
+                        if let Some(nx) = self.wrap_x(start_x as i32 + dx) {
+                            if ny >= 0 && ny < h as i32 && self.fillable(img, (ny * w as i32 + nx) as usize) {
+                                q.push_back((nx as usize, ny as usize, 0));
+                                found = true;
+                                break;
+                            }
+                        }
+                    }
+                    if found { break; }
+                }
+                if found { break; }
+            }
+            if !found { return None; }
+        }
+        let start = q.front().map(|&(x, y, _)| (x, y));
+
+        // Breadth-first, so `d` is the path length the first time a pixel is reached
+        let limit = self.config.geodesic_limit.unwrap_or(f32::INFINITY);
+        let mut visited = vec![false; img.len()];
+        while let Some((x, y, d)) = q.pop_front() {
+            let idx = y * w + x;
+            if !visited[idx] && self.fillable(img, idx) && d as f32 <= limit {
+                visited[idx] = true;
+                out[idx] = label;
+                let d = d + 1;
+                if let Some(nx) = self.wrap_x(x as i32 - 1) { q.push_back((nx as usize, y, d)); }
+                if let Some(nx) = self.wrap_x(x as i32 + 1) { q.push_back((nx as usize, y, d)); }
+                if y > 0 { q.push_back((x, y - 1, d)); }
+                if y < h - 1 { q.push_back((x, y + 1, d)); }
+            }
+        }
+        start
+    }
+
+    /// `flood_fill` through a coarse pyramid level (`set_pyramid_fill`).
+    fn pyramid_fill(&self, img: &[u8], out: &mut [u8], sx: usize, sy: usize) -> Option<(usize, usize)> {
+        let (w, h) = (self.width, self.height);
+        let s = 1usize << self.config.pyramid_levels;
+        let (cw, ch) = (w / s, h / s);
+        let plain = self.config.pyramid_levels == 0
+            || cw == 0
+            || ch == 0
+            || self.config.geodesic_limit.is_some()
+            || self.config.border_mode == BorderMode::Wrap
+            || !self.fillable(img, sy * w + sx);
+        if plain || sx / s >= cw || sy / s >= ch {
+            return self.flood_fill(img, out, sx, sy);
+        }
+        // Partial blocks past the last full one are left to the fine pass
+        let coarse: Vec<u8> = (0..cw * ch)
+            .map(|c| {
+                let (bx, by) = ((c % cw) * s, (c / cw) * s);
+                (by..by + s).all(|y| (bx..bx + s).all(|x| self.fillable(img, y * w + x))) as u8
+            })
+            .collect();
+        let seed_block = (sy / s) * cw + sx / s;
+        if coarse[seed_block] == 0 {
+            return self.flood_fill(img, out, sx, sy);
+        }
+        // Coarse component, in breadth-first order
+        let mut filled = vec![0u8; cw * ch];
+        filled[seed_block] = 1;
+        let mut reached = vec![seed_block];
+        let mut k = 0;
+        while k < reached.len() {
+            let c = reached[k];
+            k += 1;
+            let (x, y) = (c % cw, c / cw);
+            for (ok, n) in [(x > 0, c.wrapping_sub(1)), (x + 1 < cw, c + 1), (y > 0, c.wrapping_sub(cw)), (y + 1 < ch, c + cw)] {
+                if ok && coarse[n] != 0 && filled[n] == 0 {
+                    filled[n] = 1;
+                    reached.push(n);
+                }
+            }
+        }
+        let foot = |x: usize, y: usize| {
+            let (cx, cy) = ((x / s).min(cw - 1) as i32, (y / s).min(ch - 1) as i32);
+            (-1..=1).any(|dy| {
+                (-1..=1).any(|dx| {
+                    let (nx, ny) = (cx + dx, cy + dy);
+                    nx >= 0 && ny >= 0 && nx < cw as i32 && ny < ch as i32 && filled[ny as usize * cw + nx as usize] != 0
+                })
+            })
+        };
+
+        let mut visited = vec![false; w * h];
+        let mut q = std::collections::VecDeque::new();
+        for &c in &reached {
+            let (bx, by) = ((c % cw) * s, (c / cw) * s);
+            for y in by..by + s {
+                for x in bx..bx + s {
+                    visited[y * w + x] = true;
+                    out[y * w + x] = 1;
+                    if x == bx || y == by || x == bx + s - 1 || y == by + s - 1 {
+                        q.push_back((x, y));
+                    }
+                }
+            }
+        }
+        while let Some((x, y)) = q.pop_front() {
+            let mut visit = |nx: usize, ny: usize| {
+                let n = ny * w + nx;
+                if !visited[n] && self.fillable(img, n) && foot(nx, ny) {
+                    visited[n] = true;
+                    out[n] = 1;
+                    q.push_back((nx, ny));
+                }
+            };
+            if x > 0 { visit(x - 1, y); }
+            if x + 1 < w { visit(x + 1, y); }
+            if y > 0 { visit(x, y - 1); }
+            if y + 1 < h { visit(x, y + 1); }
+        }
+        Some((sx, sy))
+    }
+
+    /// Keep only the `budget` pixels of `isolated` nearest to `starts` along
+    /// 4-connected paths inside it, in breadth-first order.
+    fn limit_area(&self, isolated: &mut [u8], starts: &[(usize, usize)], budget: usize) {
+        let (w, h) = (self.width, self.height);
+        let mut kept = vec![0u8; isolated.len()];
+        let mut q: std::collections::VecDeque<(usize, usize)> = starts.iter().copied().collect();
+        let mut count = 0;
+        while let Some((x, y)) = q.pop_front() {
+            let idx = y * w + x;
+            if kept[idx] != 0 || isolated[idx] == 0 {
+                continue;
+            }
+            kept[idx] = isolated[idx];
+            count += 1;
+            if count == budget {
+                break;
+            }
+            if let Some(nx) = self.wrap_x(x as i32 - 1) { q.push_back((nx as usize, y)); }
+            if let Some(nx) = self.wrap_x(x as i32 + 1) { q.push_back((nx as usize, y)); }
+            if y > 0 { q.push_back((x, y - 1)); }
+            if y < h - 1 { q.push_back((x, y + 1)); }
+        }
+        isolated.copy_from_slice(&kept);
+    }
+
+    /// Whether a flood fill from `(x, y)` would find a pixel to start from.
+    fn seedable_near(&self, img: &[u8], x: usize, y: usize) -> bool {
+        let r = SEED_SEARCH_RADIUS;
+        (-r..=r).any(|dy| {
+            let ny = y as i32 + dy;
+            ny >= 0
+                && ny < self.height as i32
+                && (-r..=r).any(|dx| {
+                    self.wrap_x(x as i32 + dx).is_some_and(|nx| self.fillable(img, (ny * self.width as i32 + nx) as usize))
+                })
+        })
+    }
+
+    /// Whether the flood fill may enter pixel `idx` of `img`.
+    fn fillable(&self, img: &[u8], idx: usize) -> bool {
+        img[idx] == 1
+            && self.barrier.get(idx).is_none_or(|&b| b == 0)
+            && self.flood_allowed.get(idx).is_none_or(|&a| a != 0)
+    }
+
+    /// The `(dx, dy)` offsets of the structuring element of `radius`, built once
+    /// per radius and reused across frames until the element shape, length,
+    /// rotation or pixel aspect changes. Only the last `KERNEL_CACHE_SIZE`
+    /// radii are kept.
+    fn kernel_offsets(&self, radius: i32) -> Kernel {
+        if let Some((_, offsets)) = self.kernel_cache.borrow().iter().find(|(r, _)| *r == radius) {
+            return offsets.clone();
+        }
+        let reach = self.kernel_reach(radius);
+        let offsets: Kernel = (-reach..=reach)
+            .flat_map(|dy| (-reach..=reach).map(move |dx| (dx, dy)))
+            .filter(|&(dx, dy)| self.in_kernel(dx, dy, radius))
+            .collect();
+        self.kernel_builds.set(self.kernel_builds.get() + 1);
+        let mut cache = self.kernel_cache.borrow_mut();
+        if cache.len() >= KERNEL_CACHE_SIZE {
+            cache.remove(0);
+        }
+        cache.push((radius, offsets.clone()));
+        offsets
+    }
+
+    /// Whether offset `(dx, dy)` lies inside the structuring element of `radius`.
+    fn in_kernel(&self, dx: i32, dy: i32, radius: i32) -> bool {
+        let sy = dy as f32 * self.config.pixel_aspect;
+        if self.config.element_rotation != 0.0 && self.config.element_shape != ElementShape::Circle {
+            // Offset in the element's own axes; the margin keeps exact
+            // quarter turns from losing edge pixels to rounding
+            let (sin, cos) = self.config.element_rotation.sin_cos();
+            let u = (dx as f32 * cos + sy * sin).abs() - 1e-4;
+            let v = (sy * cos - dx as f32 * sin).abs() - 1e-4;
+            let half = self.line_half_length(radius) as f32;
+            return match self.config.element_shape {
+                ElementShape::HorizontalLine => v <= 0.5 && u <= half,
+                ElementShape::VerticalLine => u <= 0.5 && v <= half,
+                _ => u <= radius as f32 && v <= radius as f32,
+            };
+        }
+        match self.config.element_shape {
+            ElementShape::Circle if self.config.pixel_aspect == 1.0 => dx * dx + dy * dy <= radius * radius,
+            ElementShape::Circle => (dx * dx) as f32 + sy * sy <= (radius * radius) as f32,
+            ElementShape::Square => dx.abs() <= radius && sy.abs() <= radius as f32,
+            ElementShape::HorizontalLine => dy == 0 && dx.abs() <= self.line_half_length(radius),
+            ElementShape::VerticalLine => dx == 0 && dy.abs() <= self.line_half_length(radius),
+        }
+    }
+
+    /// Reach of line elements either side of the centre: `length / 2`, so an
+    /// even `element_length` gives a line of `length + 1` pixels.
+    fn line_half_length(&self, radius: i32) -> i32 {
+        if self.config.element_length > 0 { (self.config.element_length / 2) as i32 } else { radius }
+    }
+
+    /// Largest offset the element of `radius` can reach along either axis.
+    fn kernel_reach(&self, radius: i32) -> i32 {
+        let reach = match self.config.element_shape {
+            ElementShape::HorizontalLine | ElementShape::VerticalLine => self.line_half_length(radius),
+            _ => radius,
+        };
+        if self.config.element_rotation != 0.0 && self.config.element_shape != ElementShape::Circle {
+            (reach as f32 * std::f32::consts::SQRT_2).ceil() as i32
+        } else {
+            reach
+        }
+    }
+
+    /// Map a column index through the border mode. Returns `None` when the
+    /// column is outside the frame and the mode does not wrap.
+    fn wrap_x(&self, x: i32) -> Option<i32> {
+        let w = self.width as i32;
+        if x >= 0 && x < w {
+            Some(x)
+        } else if self.config.border_mode == BorderMode::Wrap && w > 0 {
+            Some(x.rem_euclid(w))
+        } else {
+            None
         }
     }
 
-    /// Process a new mask frame:
-    /// 1. Temporal smoothing
-    /// 2. Morphology (Erosion + Dilation)
-    /// 3. Component Isolation (seeding from click)
-    pub fn refine_mask(&mut self, input_mask: &[f32], click_x: f32, click_y: f32) -> Vec<f32> {
-        let size = self.width * self.height;
-        if input_mask.len() != size {
-            return input_mask.to_vec(); // Fallback if size mismatch
+    /// Symmetric boundary distance behind `hausdorff`: each directed distance
+    /// is the `quantile` of the distances from one boundary's pixels to the
+    /// other boundary, read off a distance transform of the other boundary.
+    fn boundary_distance(&self, a: &[f32], b: &[f32], threshold: f32, quantile: f32) -> f32 {
+        let (w, h) = (self.width, self.height);
+        let diagonal = (w as f32).hypot(h as f32);
+        if a.len() != w * h || b.len() != w * h {
+            return diagonal;
         }
+        let boundary = |m: &[f32]| -> Vec<u8> {
+            let fg = |x: usize, y: usize| m[y * w + x] > threshold;
+            (0..w * h)
+                .map(|i| {
+                    let (x, y) = (i % w, i / w);
+                    let edge = x == 0 || y == 0 || x + 1 == w || y + 1 == h
+                        || !fg(x - 1, y) || !fg(x + 1, y) || !fg(x, y - 1) || !fg(x, y + 1);
+                    (fg(x, y) && edge) as u8
+                })
+                .collect()
+        };
+        let (ba, bb) = (boundary(a), boundary(b));
+        match (ba.contains(&1), bb.contains(&1)) {
+            (false, false) => return 0.0,
+            (true, true) => {}
+            _ => return diagonal,
+        }
+        let directed = |from: &[u8], to: &[u8]| -> f32 {
+            let away: Vec<u8> = to.iter().map(|&v| (v == 0) as u8).collect();
+            let dist = self.distance_transform(&away, false);
+            let mut d: Vec<f32> = from.iter().zip(&dist).filter(|(&f, _)| f != 0).map(|(_, &d)| d).collect();
+            d.sort_by(f32::total_cmp);
+            let k = ((d.len() as f32 * quantile).ceil() as usize).clamp(1, d.len());
+            d[k - 1]
+        };
+        directed(&ba, &bb).max(directed(&bb, &ba))
+    }
 
-        // 1. Add to history and calculate temporal average
-        let mut averaged_mask = vec![0.0; size];
-        self.history.push(input_mask.to_vec());
-        if self.history.len() > self.max_history {
-            self.history.remove(0);
+    /// Exact Euclidean distance from every foreground pixel of `binary` to the
+    /// nearest background pixel (0 on background), as true (not squared)
+    /// distances. Outside the frame counts as background when
+    /// `edge_background`, otherwise it is ignored; with no background at all
+    /// every pixel gets a huge value (about 1e10). Shared by the trimap, SDF,
+    /// neck and kernel-suggestion paths.
+    fn distance_transform(&self, binary: &[u8], edge_background: bool) -> Vec<f32> {
+        // Felzenszwalb-Huttenlocher: the squared distance separates into a 1D
+        // lower envelope of parabolas per column, then per row, O(n) overall.
+        // With `edge_background` the grid is padded by a ring of background.
+        debug_assert_eq!(binary.len(), self.width * self.height);
+        let pad = edge_background as usize;
+        let w = self.width + 2 * pad;
+        let h = self.height + 2 * pad;
+        let inf = 1e20f64;
+        let mut d = vec![0.0f64; w * h];
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if binary[y * self.width + x] != 0 {
+                    d[(y + pad) * w + x + pad] = inf;
+                }
+            }
         }
 
-        let history_len = self.history.len() as f32;
-        for h in &self.history {
-            for i in 0..size {
-                averaged_mask[i] += h[i] / history_len;
+        let mut f = vec![0.0f64; w.max(h)];
+        let mut out = vec![0.0f64; w.max(h)];
+        for x in 0..w {
+            for y in 0..h {
+                f[y] = d[y * w + x];
             }
+            Self::envelope_1d(&f[..h], &mut out[..h]);
+            for y in 0..h {
+                d[y * w + x] = out[y];
+            }
+        }
+        for y in 0..h {
+            f[..w].copy_from_slice(&d[y * w..(y + 1) * w]);
+            Self::envelope_1d(&f[..w], &mut d[y * w..(y + 1) * w]);
         }
 
-        // 2. Thresholding and Erosion
-        // We erode to break "bridges" between touching objects
-        let mut eroded = vec![0u8; size];
-        let threshold = 0.5;
-        for i in 0..size {
-            if averaged_mask[i] > threshold {
-                eroded[i] = 1;
+        let mut dist = Vec::with_capacity(self.width * self.height);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                dist.push(d[(y + pad) * w + x + pad].sqrt() as f32);
             }
         }
+        dist
+    }
 
-        let kernel_size = 5; // Adjust based on needs
-        let eroded = self.erode(&eroded, kernel_size);
+    /// 1D squared distance transform of the sampled function `f` (0 on
+    /// background, a huge value elsewhere) into `out`: the lower envelope of
+    /// the parabolas `(q - p)^2 + f[p]`.
+    fn envelope_1d(f: &[f64], out: &mut [f64]) {
+        let n = f.len();
+        if n == 0 {
+            return;
+        }
+        // Parabola apexes in the envelope and where each takes over
+        let mut v = vec![0usize; n];
+        let mut z = vec![0.0f64; n + 1];
+        let mut k = 0;
+        z[0] = f64::NEG_INFINITY;
+        z[1] = f64::INFINITY;
+        for q in 1..n {
+            loop {
+                let p = v[k];
+                let s = ((f[q] + (q * q) as f64) - (f[p] + (p * p) as f64)) / (2 * (q - p)) as f64;
+                if s <= z[k] && k > 0 {
+                    k -= 1;
+                } else {
+                    k += 1;
+                    v[k] = q;
+                    z[k] = s;
+                    z[k + 1] = f64::INFINITY;
+                    break;
+                }
+            }
+        }
+        k = 0;
+        for (q, o) in out.iter_mut().enumerate() {
+            while z[k + 1] < q as f64 {
+                k += 1;
+            }
+            let p = v[k] as f64;
+            *o = (q as f64 - p) * (q as f64 - p) + f[v[k]];
+        }
+    }
 
-        // 3. Flood Fill (Connected Component) to isolate the clicked object
-        let clx = (click_x * self.width as f32) as usize;
-        let cly = (click_y * self.height as f32) as usize;
-        let mut isolated = vec![0u8; size];
+    /// One-pixel-wide skeleton of a binary image (Zhang-Suen thinning), with
+    /// diagonal steps filled in from `img` so the result is 4-connected like
+    /// the flood fill. Outside the frame counts as background unless wrapping.
+    fn skeleton(&self, img: &[u8]) -> Vec<u8> {
+        let w = self.width as i32;
+        let h = self.height as i32;
+        let mut skel = img.to_vec();
+        let at = |s: &[u8], x: i32, y: i32| -> u8 {
+            match self.wrap_x(x) {
+                Some(nx) if y >= 0 && y < h => s[(y * w + nx) as usize],
+                _ => 0,
+            }
+        };
+        // Neighbours clockwise from north
+        const RING: [(i32, i32); 8] = [(0, -1), (1, -1), (1, 0), (1, 1), (0, 1), (-1, 1), (-1, 0), (-1, -1)];
 
-        if clx < self.width && cly < self.height {
-            self.flood_fill(&eroded, &mut isolated, clx, cly);
-        } else {
-            // If click is out of bounds, fallback to full eroded
-            isolated = eroded.clone();
+        let mut changed = true;
+        while changed {
+            changed = false;
+            for pass in 0..2 {
+                let mut remove = Vec::new();
+                for y in 0..h {
+                    for x in 0..w {
+                        if skel[(y * w + x) as usize] == 0 {
+                            continue;
+                        }
+                        let p: Vec<u8> = RING.iter().map(|&(dx, dy)| at(&skel, x + dx, y + dy)).collect();
+                        let count: u8 = p.iter().sum();
+                        let transitions = (0..8).filter(|&k| p[k] == 0 && p[(k + 1) % 8] == 1).count();
+                        // N, E, S, W are p[0], p[2], p[4], p[6]
+                        let (a, b) = if pass == 0 {
+                            (p[0] * p[2] * p[4], p[2] * p[4] * p[6])
+                        } else {
+                            (p[0] * p[2] * p[6], p[0] * p[4] * p[6])
+                        };
+                        if (2..=6).contains(&count) && transitions == 1 && a == 0 && b == 0 {
+                            remove.push((y * w + x) as usize);
+                        }
+                    }
+                }
+                changed |= !remove.is_empty();
+                for i in remove {
+                    skel[i] = 0;
+                }
+            }
         }
 
-        // 4. Dilation to restore edges
-        let dilated = self.dilate(&isolated, kernel_size);
-
-        // 5. Re-apply original confidence values to the isolated blob
-        let mut final_mask = vec![0.0; size];
-        for i in 0..size {
-            if dilated[i] > 0 && input_mask[i] > 0.1 {
-                // Keep the smooth edges of the original AI mask, but only within our isolated zone
-                final_mask[i] = input_mask[i];
+        // Bridge diagonal-only steps through a shared 4-neighbour
+        let thin = skel.clone();
+        for y in 0..h {
+            for x in 0..w {
+                if thin[(y * w + x) as usize] == 0 {
+                    continue;
+                }
+                // Only look down; the upward diagonals are handled from the other end
+                for dx in [-1, 1] {
+                    if at(&thin, x + dx, y + 1) == 1 && at(&thin, x + dx, y) == 0 && at(&thin, x, y + 1) == 0 {
+                        let (fx, fy) = if at(img, x + dx, y) == 1 { (x + dx, y) } else { (x, y + 1) };
+                        if let Some(nx) = self.wrap_x(fx) {
+                            if fy < h {
+                                skel[(fy * w + nx) as usize] = 1;
+                            }
+                        }
+                    }
+                }
             }
         }
+        skel
+    }
 
-        final_mask
+    /// Rec. 601 luma (0.299 R + 0.587 G + 0.114 B) of an RGBA buffer, in 0-1.
+    fn luminance(&self, src: &[u8]) -> Vec<f32> {
+        src.chunks_exact(4)
+            .map(|p| (0.299 * p[0] as f32 + 0.587 * p[1] as f32 + 0.114 * p[2] as f32) / 255.0)
+            .collect()
     }
 
-    fn erode(&self, img: &[u8], radius: i32) -> Vec<u8> {
-        let mut out = vec![0; img.len()];
+    /// Sobel gradient magnitude of a single-channel image, edges clamped.
+    fn sobel(&self, img: &[f32]) -> Vec<f32> {
         let w = self.width as i32;
         let h = self.height as i32;
+        let px = |x: i32, y: i32| img[(y.clamp(0, h - 1) * w + x.clamp(0, w - 1)) as usize];
+        let mut out = vec![0.0; img.len()];
 
         for y in 0..h {
             for x in 0..w {
-                let mut min_val = 1;
-                for dy in -radius..=radius {
-                    for dx in -radius..=radius {
-                        if dx * dx + dy * dy <= radius * radius {
-                            let nx = x + dx;
-                            let ny = y + dy;
-                            if nx >= 0 && nx < w && ny >= 0 && ny < h {
-                                let idx = (ny * w + nx) as usize;
-                                if img[idx] == 0 {
-                                    min_val = 0;
-                                }
-                            } else {
-                                min_val = 0;
-                            }
-                        }
+                let gx = px(x + 1, y - 1) + 2.0 * px(x + 1, y) + px(x + 1, y + 1)
+                    - px(x - 1, y - 1) - 2.0 * px(x - 1, y) - px(x - 1, y + 1);
+                let gy = px(x - 1, y + 1) + 2.0 * px(x, y + 1) + px(x + 1, y + 1)
+                    - px(x - 1, y - 1) - 2.0 * px(x, y - 1) - px(x + 1, y - 1);
+                out[(y * w + x) as usize] = (gx * gx + gy * gy).sqrt();
+            }
+        }
+        out
+    }
+
+    /// Set each pixel to the majority value of its (2r+1)^2 neighbourhood.
+    /// Out-of-frame neighbours are skipped.
+    fn majority_smooth(&self, img: &[u8], radius: i32) -> Vec<u8> {
+        let w = self.width as i32;
+        let h = self.height as i32;
+        let mut out = vec![0u8; img.len()];
+
+        for y in 0..h {
+            for x in 0..w {
+                let mut on = 0;
+                let mut total = 0;
+                for ny in (y - radius).max(0)..=(y + radius).min(h - 1) {
+                    for nx in (x - radius).max(0)..=(x + radius).min(w - 1) {
+                        on += img[(ny * w + nx) as usize] as i32;
+                        total += 1;
                     }
                 }
-                out[(y * w + x) as usize] = min_val;
+                out[(y * w + x) as usize] = (2 * on > total) as u8;
             }
         }
         out
     }
 
-    fn dilate(&self, img: &[u8], radius: i32) -> Vec<u8> {
-        let mut out = vec![0; img.len()];
+    /// Scale `mask` in place so its approximate 99th percentile becomes 1.0.
+    /// Returns the gain applied (1.0 when the frame was left as-is).
+    fn apply_auto_gain(mask: &mut [f32]) -> f32 {
+        let mut bins = [0usize; 256];
+        for &v in mask.iter() {
+            bins[(v.clamp(0.0, 1.0) * 255.0) as usize] += 1;
+        }
+
+        let target = mask.len() - mask.len() / 100;
+        let mut seen = 0;
+        let mut p99 = 1.0;
+        for (b, &n) in bins.iter().enumerate() {
+            seen += n;
+            if seen >= target {
+                p99 = (b as f32 + 1.0) / 256.0;
+                break;
+            }
+        }
+
+        if p99 < 0.05 {
+            return 1.0;
+        }
+        let gain = 1.0 / p99;
+        for v in mask.iter_mut() {
+            *v = (*v * gain).min(1.0);
+        }
+        gain
+    }
+
+    /// 3x3 median filter. Edge pixels reuse the nearest in-frame neighbours.
+    fn median3(&self, img: &[f32]) -> Vec<f32> {
         let w = self.width as i32;
         let h = self.height as i32;
+        let mut out = vec![0.0; img.len()];
+        let mut window = [0.0f32; 9];
 
         for y in 0..h {
             for x in 0..w {
-                if img[(y * w + x) as usize] == 1 {
-                    for dy in -radius..=radius {
-                        for dx in -radius..=radius {
-                            if dx * dx + dy * dy <= radius * radius {
-                                let nx = x + dx;
-                                let ny = y + dy;
-                                if nx >= 0 && nx < w && ny >= 0 && ny < h {
-                                    out[(ny * w + nx) as usize] = 1;
-                                }
-                            }
-                        }
+                let mut n = 0;
+                for dy in -1..=1 {
+                    for dx in -1..=1 {
+                        let nx = (x + dx).clamp(0, w - 1);
+                        let ny = (y + dy).clamp(0, h - 1);
+                        window[n] = img[(ny * w + nx) as usize];
+                        n += 1;
                     }
                 }
+                window.sort_unstable_by(|a, b| a.total_cmp(b));
+                out[(y * w + x) as usize] = window[4];
             }
         }
         out
     }
 
-    fn flood_fill(&self, img: &[u8], out: &mut [u8], start_x: usize, start_y: usize) {
+    /// Label the background of `fg` and measure its holes. Returns the
+    /// per-pixel background labels and, per label, the area of that region if
+    /// it is a hole (doesn't reach the frame edge) or `None` if it is open.
+    fn hole_areas(&self, fg: &[u8]) -> (Vec<u32>, Vec<Option<usize>>) {
+        let background: Vec<u8> = fg.iter().map(|&v| (v == 0) as u8).collect();
+        let (labels, count) = self.label_components(&background, 0);
+        let mut areas = vec![Some(0); count + 1];
+        areas[0] = None;
+        let (w, h) = (self.width, self.height);
+        for (i, &l) in labels.iter().enumerate() {
+            let (x, y) = (i % w, i / w);
+            if x == 0 || y == 0 || x == w - 1 || y == h - 1 {
+                areas[l as usize] = None;
+            } else if let Some(a) = areas[l as usize].as_mut() {
+                *a += 1;
+            }
+        }
+        (labels, areas)
+    }
+
+    /// Set holes of `final_mask` smaller than `max_hole_area` to 1.0.
+    fn fill_small_holes(&self, final_mask: &mut [f32]) {
+        if self.config.max_hole_area == 0 {
+            return;
+        }
+        let fg: Vec<u8> = final_mask.iter().map(|&v| (v > 0.0) as u8).collect();
+        let (labels, areas) = self.hole_areas(&fg);
+        for (v, &l) in final_mask.iter_mut().zip(&labels) {
+            if areas[l as usize].is_some_and(|a| a < self.config.max_hole_area as usize) {
+                *v = 1.0;
+            }
+        }
+    }
+
+    /// Label the 4-connected foreground components of a binary image.
+    /// Returns the per-pixel label (0 = background, components numbered from 1
+    /// in row-major order of their first pixel) and the component count.
+    /// Stops after `max_components` components (0 = no cap), leaving the rest labeled 0.
+    fn label_components(&self, img: &[u8], max_components: usize) -> (Vec<u32>, usize) {
         let w = self.width;
         let h = self.height;
+        let mut labels = vec![0u32; img.len()];
+        let mut count = 0;
+        let mut stack = Vec::new();
 
-        let start_idx = start_y * w + start_x;
-        
-        // Find nearest 1 if starting point is 0
-        let mut q = std::collections::VecDeque::new();
-        
-        if img[start_idx] == 1 {
-            q.push_back((start_x, start_y));
-        } else {
-            // Search nearby for a 1
-            let mut found = false;
-            let radius = 20;
-            for r in 1..=radius {
-                for dy in -r..=r {
-                    for dx in -r..=r {
-                        let nx = start_x as i32 + dx;
-                        let ny = start_y as i32 + dy;
-                        if nx >= 0 && nx < w as i32 && ny >= 0 && ny < h as i32 {
-                            if img[(ny * w as i32 + nx) as usize] == 1 {
-                                q.push_back((nx as usize, ny as usize));
-                                found = true;
-                                break;
-                            }
+        for start in 0..img.len() {
+            if img[start] == 0 || labels[start] != 0 {
+                continue;
+            }
+            if max_components > 0 && count == max_components {
+                break;
+            }
+            count += 1;
+            labels[start] = count as u32;
+            stack.push(start);
+            while let Some(idx) = stack.pop() {
+                let x = idx % w;
+                let y = idx / w;
+                let mut visit = |n: usize| {
+                    if img[n] != 0 && labels[n] == 0 {
+                        labels[n] = count as u32;
+                        stack.push(n);
+                    }
+                };
+                if x > 0 { visit(idx - 1); }
+                if x < w - 1 { visit(idx + 1); }
+                if y > 0 { visit(idx - w); }
+                if y < h - 1 { visit(idx + w); }
+            }
+        }
+        (labels, count)
+    }
+}
+
+impl SubjectRefiner {
+    /// Trace the boundary of every component in `mask`, including interior holes.
+    ///
+    /// Each ring is a flat `[x0, y0, x1, y1, ...]` list of pixel-corner
+    /// coordinates, simplified with Douglas-Peucker at `epsilon` pixels (0 only
+    /// drops collinear points). Components are emitted in row-major order of
+    /// their first pixel, each as its outer ring followed by its hole rings.
+    /// Outer rings wind clockwise on screen (y down) and holes counter-clockwise,
+    /// so the rings fill correctly under both even-odd and non-zero rules. A
+    /// component sitting inside another's hole is simply its own outer ring.
+    ///
+    /// Returns an empty list if `mask` is not `width * height` long.
+    pub fn contours_with_holes(&self, mask: &[u8], epsilon: f32) -> Vec<Vec<f32>> {
+        let w = self.width;
+        let h = self.height;
+        if mask.len() != w * h {
+            return Vec::new();
+        }
+
+        let (labels, count) = self.label_components(mask, 0);
+        let fg = |x: i32, y: i32| -> u32 {
+            if x >= 0 && y >= 0 && (x as usize) < w && (y as usize) < h {
+                labels[y as usize * w + x as usize]
+            } else {
+                0
+            }
+        };
+
+        // Directed crack edges with foreground on the right-hand side,
+        // stored as a bitmask of outgoing directions per vertex (E, S, W, N).
+        const STEP: [(i32, i32); 4] = [(1, 0), (0, 1), (-1, 0), (0, -1)];
+        let vw = w + 1;
+        let mut edges = vec![0u8; vw * (h + 1)];
+        for y in 0..h as i32 {
+            for x in 0..w as i32 {
+                if fg(x, y) == 0 {
+                    continue;
+                }
+                let v = |vx: i32, vy: i32| vy as usize * vw + vx as usize;
+                if fg(x, y - 1) == 0 { edges[v(x, y)] |= 1; }
+                if fg(x + 1, y) == 0 { edges[v(x + 1, y)] |= 2; }
+                if fg(x, y + 1) == 0 { edges[v(x + 1, y + 1)] |= 4; }
+                if fg(x - 1, y) == 0 { edges[v(x, y + 1)] |= 8; }
+            }
+        }
+
+        let mut rings: Vec<Vec<(bool, Vec<f32>)>> = vec![Vec::new(); count];
+        for start in 0..edges.len() {
+            while edges[start] != 0 {
+                let start_dir = edges[start].trailing_zeros() as usize;
+                let (sx, sy) = ((start % vw) as i32, (start / vw) as i32);
+                // The pixel on the right of the first edge owns this ring.
+                let label = match start_dir {
+                    0 => fg(sx, sy),
+                    1 => fg(sx - 1, sy),
+                    2 => fg(sx - 1, sy - 1),
+                    _ => fg(sx, sy - 1),
+                };
+
+                let mut ring = Vec::new();
+                let (mut x, mut y, mut dir) = (sx, sy, start_dir);
+                loop {
+                    let v = y as usize * vw + x as usize;
+                    edges[v] &= !(1 << dir);
+                    ring.push(x as f32);
+                    ring.push(y as f32);
+                    x += STEP[dir].0;
+                    y += STEP[dir].1;
+
+                    // Prefer turning right, so diagonal-only neighbours stay
+                    // separate as in the 4-connected labeling.
+                    let v = y as usize * vw + x as usize;
+                    let mut avail = edges[v];
+                    if v == start {
+                        avail |= 1 << start_dir;
+                    }
+                    let next = [1, 0, 3]
+                        .iter()
+                        .map(|turn| (dir + turn) % 4)
+                        .find(|d| avail & (1 << d) != 0);
+                    match next {
+                        Some(d) if v == start && d == start_dir => break,
+                        Some(d) => dir = d,
+                        None => break,
+                    }
+                }
+                if label > 0 {
+                    let outer = Self::ring_area(&ring) > 0.0;
+                    rings[label as usize - 1].push((outer, ring));
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        for mut component in rings {
+            // Outer ring (positive area) first, holes after.
+            component.sort_by_key(|(outer, _)| !*outer);
+            for (_, ring) in component {
+                out.push(Self::simplify_ring(&ring, epsilon));
+            }
+        }
+        out
+    }
+
+    /// Signed shoelace area of a flat ring; positive for clockwise on screen.
+    fn ring_area(ring: &[f32]) -> f32 {
+        let n = ring.len() / 2;
+        let mut sum = 0.0;
+        for i in 0..n {
+            let j = (i + 1) % n;
+            sum += ring[2 * i] * ring[2 * j + 1] - ring[2 * j] * ring[2 * i + 1];
+        }
+        sum / 2.0
+    }
+
+    /// Douglas-Peucker simplification of a closed flat ring.
+    fn simplify_ring(ring: &[f32], epsilon: f32) -> Vec<f32> {
+        let pts: Vec<(f32, f32)> = ring.chunks_exact(2).map(|p| (p[0], p[1])).collect();
+        if pts.len() < 4 {
+            return ring.to_vec();
+        }
+
+        // Split the ring at the vertex farthest from the first one and
+        // simplify both halves as open polylines.
+        let far = (1..pts.len())
+            .max_by(|&a, &b| {
+                let da = (pts[a].0 - pts[0].0).powi(2) + (pts[a].1 - pts[0].1).powi(2);
+                let db = (pts[b].0 - pts[0].0).powi(2) + (pts[b].1 - pts[0].1).powi(2);
+                da.total_cmp(&db)
+            })
+            .unwrap_or(0);
+        let mut keep = vec![false; pts.len() + 1];
+        keep[0] = true;
+        keep[far] = true;
+        keep[pts.len()] = true;
+        let closed: Vec<(f32, f32)> = pts.iter().copied().chain(std::iter::once(pts[0])).collect();
+        Self::simplify_span(&closed, 0, far, epsilon.max(0.0), &mut keep);
+        Self::simplify_span(&closed, far, pts.len(), epsilon.max(0.0), &mut keep);
+
+        let mut out = Vec::new();
+        for (i, p) in pts.iter().enumerate() {
+            if keep[i] {
+                out.push(p.0);
+                out.push(p.1);
+            }
+        }
+        out
+    }
+
+    /// Convex hull of the last selection's pixel corners.
+    fn selection_hull(&self) -> Vec<(f32, f32)> {
+        let binary: Vec<u8> = self.last_final.iter().map(|&v| (v > 0.0) as u8).collect();
+        self.pixel_hull(&binary)
+    }
+
+    /// Convex hull of the corners of the foreground pixels of `mask`. The outer
+    /// corners of each row's first and last pixel are enough to span it.
+    fn pixel_hull(&self, mask: &[u8]) -> Vec<(f32, f32)> {
+        let mut pts = Vec::new();
+        for (y, row) in mask.chunks_exact(self.width.max(1)).enumerate() {
+            let first = row.iter().position(|&v| v != 0);
+            let last = row.iter().rposition(|&v| v != 0);
+            if let (Some(a), Some(b)) = (first, last) {
+                let (a, b, y) = (a as f32, (b + 1) as f32, y as f32);
+                pts.extend_from_slice(&[(a, y), (a, y + 1.0), (b, y), (b, y + 1.0)]);
+            }
+        }
+        Self::convex_hull(pts)
+    }
+
+    /// Convex hull of `pts` in counter-clockwise order (Andrew's monotone chain),
+    /// without collinear points.
+    fn convex_hull(mut pts: Vec<(f32, f32)>) -> Vec<(f32, f32)> {
+        pts.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+        pts.dedup();
+        if pts.len() < 3 {
+            return pts;
+        }
+        // One monotone chain, dropping points that don't turn left
+        fn chain(pts: impl Iterator<Item = (f32, f32)>) -> Vec<(f32, f32)> {
+            let cross = |o: (f32, f32), a: (f32, f32), b: (f32, f32)| (a.0 - o.0) * (b.1 - o.1) - (a.1 - o.1) * (b.0 - o.0);
+            let mut out: Vec<(f32, f32)> = Vec::new();
+            for p in pts {
+                while out.len() >= 2 && cross(out[out.len() - 2], out[out.len() - 1], p) <= 0.0 {
+                    out.pop();
+                }
+                out.push(p);
+            }
+            out.pop();
+            out
+        }
+        let mut hull = chain(pts.iter().copied());
+        hull.extend(chain(pts.iter().rev().copied()));
+        hull
+    }
+
+    fn simplify_span(pts: &[(f32, f32)], a: usize, b: usize, epsilon: f32, keep: &mut [bool]) {
+        if b <= a + 1 {
+            return;
+        }
+        let (ax, ay) = pts[a];
+        let (bx, by) = pts[b];
+        let len = ((bx - ax).powi(2) + (by - ay).powi(2)).sqrt();
+        let mut best = a;
+        let mut best_d = -1.0;
+        for (i, &(px, py)) in pts.iter().enumerate().take(b).skip(a + 1) {
+            let d = if len > 0.0 {
+                ((bx - ax) * (ay - py) - (ax - px) * (by - ay)).abs() / len
+            } else {
+                ((px - ax).powi(2) + (py - ay).powi(2)).sqrt()
+            };
+            if d > best_d {
+                best_d = d;
+                best = i;
+            }
+        }
+        if best_d > epsilon {
+            keep[best] = true;
+            Self::simplify_span(pts, a, best, epsilon, keep);
+            Self::simplify_span(pts, best, b, epsilon, keep);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `w * h` frame with 1.0 wherever `f(x, y)` holds.
+    fn frame(w: usize, h: usize, f: impl Fn(usize, usize) -> bool) -> Vec<f32> {
+        (0..w * h).map(|i| if f(i % w, i / w) { 1.0 } else { 0.0 }).collect()
+    }
+
+    /// Refiner with small radii and no temporal smoothing.
+    fn refiner(w: usize, h: usize) -> SubjectRefiner {
+        let mut r = SubjectRefiner::new(w, h, 1);
+        r.set_erode_radius(1);
+        r.set_dilate_radius(1);
+        r
+    }
+
+    #[test]
+    fn wrap_border_joins_blob_across_the_seam() {
+        let (w, h) = (80, 40);
+        let m = frame(w, h, |x, y| (10..30).contains(&y) && !(16..64).contains(&x));
+        let mut constant = refiner(w, h);
+        let out = constant.refine_mask(&m, 0.1, 0.5);
+        assert!(out[20 * w + 2] > 0.5);
+        assert_eq!(out[20 * w + 72], 0.0);
+        let mut wrap = refiner(w, h);
+        wrap.set_border_mode(1);
+        let out = wrap.refine_mask(&m, 0.1, 0.5);
+        assert!(out[20 * w + 2] > 0.5 && out[20 * w + 72] > 0.5);
+        assert!(out[20 * w] > 0.5 && out[20 * w + w - 1] > 0.5);
+    }
+
+    #[test]
+    fn median3_removes_single_pixel_noise_and_keeps_filled_regions() {
+        let (w, h) = (30, 20);
+        let clean = frame(w, h, |x, _| x < 15);
+        let mut noisy = clean.clone();
+        noisy[10 * w + 25] = 1.0;
+        noisy[10 * w + 5] = 0.0;
+        let r = SubjectRefiner::new(w, h, 1);
+        assert_eq!(r.median3(&noisy), clean);
+    }
+
+    #[test]
+    fn keep_largest_n_keeps_the_two_biggest_blobs() {
+        let (w, h) = (30, 10);
+        // Squares of side 2, 3, 4 and 5 (areas 4, 9, 16, 25), in scrambled order
+        let square = |x0: usize, side: usize| move |x: usize, y: usize| (x0..x0 + side).contains(&x) && (1..1 + side).contains(&y);
+        let (a, b, c, d) = (square(1, 4), square(7, 2), square(11, 5), square(19, 3));
+        let mask: Vec<u8> = frame(w, h, |x, y| a(x, y) || b(x, y) || c(x, y) || d(x, y)).iter().map(|&v| v as u8).collect();
+        let expected: Vec<u8> = frame(w, h, |x, y| a(x, y) || c(x, y)).iter().map(|&v| v as u8).collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        assert_eq!(r.keep_largest_n(&mask, 2), expected);
+    }
+
+    #[test]
+    fn refine_tile_leaves_the_frame_state_alone() {
+        let (w, h) = (40, 30);
+        let m = frame(w, h, |x, y| (5..35).contains(&x) && (5..25).contains(&y));
+        let mut r = refiner(w, h);
+        r.refine_mask(&m, 0.5, 0.5);
+        let eroded = r.last_eroded.clone();
+        let tile = r.refine_tile(&m, 10, 10, 10, 10, 4, 0.5, 0.5);
+        assert_eq!(tile.len(), 100);
+        assert!(tile.iter().all(|&v| v > 0.5));
+        assert_eq!(r.last_eroded, eroded);
+        assert_eq!((r.width, r.height, r.history.len()), (w, h, 1));
+    }
+
+    #[test]
+    fn reconstruct_converges_along_a_winding_component() {
+        let (w, h) = (11, 14);
+        // A snake through rows 0-10, joined alternately at the right and left ends,
+        // and a separate bar on rows 12-13
+        let snake = |x: usize, y: usize| y <= 10 && (y.is_multiple_of(2) || (y % 4 == 1 && x == 10) || (y % 4 == 3 && x == 0));
+        let mask: Vec<u8> = frame(w, h, |x, y| snake(x, y) || y >= 12).iter().map(|&v| v as u8).collect();
+        let expected: Vec<u8> = frame(w, h, snake).iter().map(|&v| v as u8).collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        let mut marker = vec![0u8; w * h];
+        marker[0] = 1;
+        marker[11 * w + 5] = 1;
+        let out = r.reconstruct(&marker, &mask);
+        assert_eq!(out, expected);
+        assert_eq!(r.reconstruct(&out, &mask), out);
+    }
+
+    #[test]
+    fn decode_mask_round_trips_and_rejects_bad_headers() {
+        let (w, h) = (30, 20);
+        let m: Vec<f32> = (0..w * h).map(|i| ((i * 7) % 300) as f32 / 299.0).collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        let bytes = r.encode_mask(&m);
+        let quantized: Vec<f32> = m.iter().map(|&v| (v * 255.0).round() / 255.0).collect();
+        assert_eq!(r.decode_mask(&bytes), quantized);
+
+        let mut wrong_size = bytes.clone();
+        wrong_size[4..8].copy_from_slice(&31u32.to_le_bytes());
+        assert!(r.decode_mask(&wrong_size).is_empty());
+        let mut overflow = bytes.clone();
+        overflow[4..12].copy_from_slice(&[0xff; 8]);
+        assert!(r.decode_mask(&overflow).is_empty());
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert!(r.decode_mask(&bad_magic).is_empty());
+        assert!(r.decode_mask(&bytes[..bytes.len() - 2]).is_empty());
+        assert!(r.decode_mask(&bytes[..bytes.len() - 1]).is_empty());
+        assert!(r.decode_mask(&bytes[..8]).is_empty());
+    }
+
+    #[test]
+    fn horizontal_line_keeps_bars_and_removes_vertical_streaks() {
+        let (w, h) = (40, 30);
+        let m = frame(w, h, |x, y| (10..13).contains(&y) && (5..35).contains(&x) || (20..22).contains(&x) && (16..28).contains(&y));
+        let mut r = SubjectRefiner::new(w, h, 1);
+        r.set_erode_radius(3);
+        r.set_dilate_radius(3);
+        r.set_element_shape(2, 6);
+        assert_eq!(r.kernel_offsets(3).len(), 7);
+        let out = r.refine_mask(&m, 2.0, 2.0);
+        assert!((5..35).all(|x| (10..13).all(|y| out[y * w + x] == 1.0)));
+        assert!((16..28).all(|y| out[y * w + 20] == 0.0 && out[y * w + 21] == 0.0));
+    }
+
+    #[test]
+    fn confidence_floor_box_is_the_isolated_component() {
+        let (w, h) = (32, 32);
+        let mut m: Vec<f32> = frame(w, h, |x, y| (10..20).contains(&x) && (10..20).contains(&y)).iter().map(|v| v * 0.6).collect();
+        m[21 * w + 21] = 0.95;
+        let mut r = refiner(w, h);
+        r.set_dilate_radius(3);
+        r.set_confidence_floor(0.9);
+        let out = r.refine_mask(&m, 15.0 / 32.0, 0.5);
+        assert!(out[15 * w + 15] > 0.5);
+        assert_eq!(out[21 * w + 21], 0.0);
+    }
+
+    #[test]
+    fn preserve_thin_keeps_the_arms_of_a_plus() {
+        let (w, h) = (31, 31);
+        let plus = frame(w, h, |x, y| (14..17).contains(&y) && (3..28).contains(&x) || (14..17).contains(&x) && (3..28).contains(&y));
+        let binary: Vec<u8> = plus.iter().map(|&v| v as u8).collect();
+        let mut r = SubjectRefiner::new(w, h, 1);
+        r.set_erode_radius(3);
+        r.set_dilate_radius(3);
+        let skel = r.skeleton(&binary);
+        for (x, y) in [(5, 15), (25, 15), (15, 5), (15, 25)] {
+            assert_eq!(skel[y * w + x], 1);
+        }
+        assert!(r.refine_mask(&plus, 0.5, 0.5).iter().all(|&v| v == 0.0));
+        r.set_preserve_thin(true);
+        let out = r.refine_mask(&plus, 0.5, 0.5);
+        for (x, y) in [(4, 15), (26, 15), (15, 4), (15, 26), (15, 15)] {
+            assert_eq!(out[y * w + x], 1.0);
+        }
+    }
+
+    #[test]
+    fn dt_aware_window_keeps_the_older_frames() {
+        let mut r = SubjectRefiner::new(1, 1, 10);
+        r.set_erode_radius(0);
+        r.set_dilate_radius(0);
+        r.set_threshold(0.65);
+        r.set_smoothing_time(100.0);
+        for _ in 0..5 {
+            r.refine_mask_dt_aware(&[1.0], 16.6, 2.0, 2.0);
+        }
+        // 2 frames at 50 ms average to 0.6, the 6 at 16.6 ms to about 0.73
+        assert_eq!(r.refine_mask_dt_aware(&[0.2], 50.0, 2.0, 2.0), vec![0.0]);
+        assert_eq!(r.refine_mask_dt_aware(&[0.2], 16.6, 2.0, 2.0), vec![0.2]);
+    }
+
+    #[test]
+    fn refine_mask_u16_scales_the_f32_result() {
+        let (w, h) = (30, 20);
+        let m: Vec<f32> = (0..w * h).map(|i| if (5..25).contains(&(i % w)) { [1.0, 0.5, 0.75][i % 3] } else { 0.0 }).collect();
+        let mut a = refiner(w, h);
+        let mut b = refiner(w, h);
+        a.set_threshold(0.3);
+        b.set_threshold(0.3);
+        let f = a.refine_mask(&m, 0.5, 0.5);
+        let q = b.refine_mask_u16(&m, 0.5, 0.5);
+        assert_eq!(q.len(), f.len());
+        for (&v, &u) in f.iter().zip(&q) {
+            assert_eq!(u, (v * 65535.0).round() as u16);
+        }
+        // Pixels 315, 316 and 317 hold 1.0, 0.5 and 0.75
+        assert_eq!(&q[315..318], &[65535, 32768, 49151]);
+    }
+
+    #[test]
+    fn component_cap_applies_to_keep_n_but_not_hole_filling() {
+        let (w, h) = (40, 40);
+        let blobs: Vec<u8> = frame(w, h, |x, y| y < 5 && (x < 5 || (10..20).contains(&x) || (25..40).contains(&x))).iter().map(|&v| v as u8).collect();
+        let mut r = refiner(w, h);
+        r.set_max_components(1);
+        let kept = r.keep_largest_n(&blobs, 3);
+        assert_eq!(kept.iter().filter(|&&v| v != 0).count(), 25);
+        assert_eq!(r.contours_with_holes(&blobs, 0.0).len(), 3);
+
+        let ring = frame(w, h, |x, y| (10..30).contains(&x) && (10..30).contains(&y) && !((18..22).contains(&x) && (18..22).contains(&y)));
+        r.fill_holes_smaller_than(50);
+        let out = r.refine_mask(&ring, 0.3, 0.3);
+        assert!(out[20 * w + 20] > 0.0);
+    }
+
+    #[test]
+    fn concavity_of_an_l_is_its_inner_corner() {
+        let (w, h) = (30, 30);
+        let l_shape: Vec<u8> = frame(w, h, |x, y| (5..10).contains(&x) && (5..25).contains(&y) || (5..25).contains(&x) && (20..25).contains(&y))
+            .iter()
+            .map(|&v| v as u8)
+            .collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        let bay = r.concavity_regions(&l_shape);
+        assert_eq!(bay[17 * w + 12], 1);
+        assert_eq!(bay[8 * w + 22], 0);
+        assert_eq!(bay[22 * w + 7], 0);
+        for (i, _) in bay.iter().enumerate().filter(|(_, &v)| v != 0) {
+            assert!((10..25).contains(&(i % w)) && (5..20).contains(&(i / w)) && i / w + 5 >= i % w);
+        }
+    }
+
+    #[test]
+    fn kernel_cache_reuses_radii_and_rebuilds_on_element_changes() {
+        let mut r = SubjectRefiner::new(16, 16, 1);
+        r.kernel_offsets(3);
+        r.kernel_offsets(3);
+        assert_eq!(r.kernel_builds(), 1);
+        r.kernel_offsets(2);
+        assert_eq!(r.kernel_builds(), 2);
+        r.set_element_shape(1, 0);
+        r.kernel_offsets(3);
+        assert_eq!(r.kernel_builds(), 3);
+        r.set_pixel_aspect(2.0);
+        r.kernel_offsets(3);
+        assert_eq!(r.kernel_builds(), 4);
+        r.set_rotation(0.5);
+        r.kernel_offsets(3);
+        r.kernel_offsets(3);
+        assert_eq!(r.kernel_builds(), 5);
+
+        for radius in 0..2 * KERNEL_CACHE_SIZE as i32 {
+            r.kernel_offsets(radius);
+        }
+        assert_eq!(r.kernel_cache.borrow().len(), KERNEL_CACHE_SIZE);
+    }
+
+    #[test]
+    fn grayscale_dilate_carries_the_component_values_into_the_band() {
+        let (w, h) = (40, 40);
+        let m: Vec<f32> = (0..w * h)
+            .map(|i| match (i % w, i / w) {
+                (10..30, 10..30) => 0.9,
+                (7..33, 7..33) => 0.2,
+                _ => 0.0,
+            })
+            .collect();
+        let mut binary = refiner(w, h);
+        binary.set_dilate_radius(2);
+        let two_step = binary.refine_mask(&m, 0.5, 0.5);
+        let mut gray = refiner(w, h);
+        gray.set_dilate_radius(2);
+        gray.set_grayscale_dilate(true);
+        let one_pass = gray.refine_mask(&m, 0.5, 0.5);
+        let support = |v: &[f32]| v.iter().map(|&x| x > 0.0).collect::<Vec<_>>();
+        assert_eq!(support(&one_pass), support(&two_step));
+        assert_eq!((two_step[20 * w + 20], one_pass[20 * w + 20]), (0.9, 0.9));
+        assert_eq!((two_step[20 * w + 9], one_pass[20 * w + 9]), (0.2, 0.9));
+        assert!(one_pass.iter().zip(&two_step).all(|(g, b)| g >= b));
+    }
+
+    #[test]
+    fn reset_config_matches_a_fresh_refiner() {
+        let (w, h) = (60, 60);
+        let m: Vec<f32> = frame(w, h, |x, y| (15..45).contains(&x) && (10..50).contains(&y)).iter().map(|v| v * 0.7).collect();
+        let mut r = SubjectRefiner::new(w, h, 4);
+        r.set_threshold(0.8);
+        r.set_erode_radius(2);
+        r.set_alpha_gamma(2.0);
+        r.set_hard_output(true);
+        r.set_element_shape(1, 0);
+        r.set_spatial_median(true);
+        r.set_output_layout(1);
+        r.reset_config();
+        let mut fresh = SubjectRefiner::new(w, h, 4);
+        assert!(r.config == fresh.config);
+        let out = r.refine_mask(&m, 0.5, 0.5);
+        assert!(out.iter().any(|&v| v > 0.0));
+        assert_eq!(out, fresh.refine_mask(&m, 0.5, 0.5));
+    }
+
+    #[test]
+    fn fill_small_holes_leaves_the_large_hole() {
+        let (w, h) = (40, 30);
+        let small = |x: usize, y: usize| (8..10).contains(&x) && (8..10).contains(&y);
+        let large = |x: usize, y: usize| (20..30).contains(&x) && (10..20).contains(&y);
+        let mut m: Vec<f32> = frame(w, h, |x, y| (4..36).contains(&x) && (4..26).contains(&y) && !small(x, y) && !large(x, y))
+            .iter()
+            .map(|v| v * 0.8)
+            .collect();
+        let mut r = SubjectRefiner::new(w, h, 1);
+        r.fill_holes_smaller_than(20);
+        r.fill_small_holes(&mut m);
+        assert_eq!(m[8 * w + 8], 1.0);
+        assert_eq!(m[9 * w + 9], 1.0);
+        assert_eq!(m[15 * w + 25], 0.0);
+        assert_eq!(m[2 * w + 2], 0.0);
+    }
+
+    #[test]
+    fn sdf_crosses_zero_at_the_edge_and_rises_inward() {
+        let (w, h) = (40, 40);
+        let square: Vec<u8> = frame(w, h, |x, y| (10..30).contains(&x) && (10..30).contains(&y)).iter().map(|&v| v as u8).collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        let sdf = r.signed_distance_field(&square);
+        let row = &sdf[20 * w..21 * w];
+        assert_eq!((row[9], row[10]), (-0.5, 0.5));
+        assert_eq!((row[29], row[30]), (0.5, -0.5));
+        assert!(row[..20].windows(2).all(|p| p[0] < p[1]));
+        assert!(row[20..].windows(2).all(|p| p[0] >= p[1]));
+    }
+
+    #[test]
+    fn sanitize_repairs_non_finite_values() {
+        let (w, h) = (20, 20);
+        let mut m = frame(w, h, |x, y| (5..15).contains(&x) && (5..15).contains(&y));
+        m[..6].copy_from_slice(&[f32::NAN, f32::INFINITY, f32::NEG_INFINITY, 3.0, -1.0, 0.4]);
+        m[10 * w + 10] = f32::NAN;
+        let mut r = refiner(w, h);
+        assert_eq!(r.sanitize(&m[..6]), [0.0, 0.0, 0.0, 1.0, 0.0, 0.4]);
+        r.set_sanitize_input(true);
+        for _ in 0..2 {
+            let out = r.refine_mask(&m, 0.5, 0.5);
+            assert!(out.iter().all(|v| (0.0..=1.0).contains(v)));
+            assert!(out[12 * w + 12] > 0.5);
+        }
+    }
+
+    #[test]
+    fn superpixel_snap_is_the_recorded_result() {
+        let (w, h) = (40, 24);
+        let src: Vec<u8> = (0..w * h).flat_map(|i| if i % w < 20 { [200, 40, 40, 255] } else { [40, 40, 200, 255] }).collect();
+        let m: Vec<f32> = frame(w, h, |x, y| x < 18 + y % 3 && (2..22).contains(&y)).iter().map(|v| v * 0.8).collect();
+        let mut r = refiner(w, h);
+        r.set_fadeout_frames(4);
+        let out = r.refine_snap_superpixels(&m, &src, 0.2, 0.5, 4);
+        assert_eq!(r.last_final, out);
+        assert_eq!(r.fade, out);
+        assert_eq!(r.edge_confidence, SubjectRefiner::edge_confidence_of(&out, &m));
+
+        let mut bad = refiner(w, h);
+        bad.refine_snap_superpixels(&m, &src[4..], 0.2, 0.5, 4);
+        assert!(bad.history.is_empty() && bad.last_final.is_empty());
+    }
+
+    #[test]
+    fn guarantee_click_is_skipped_while_the_selection_is_locked() {
+        let (w, h) = (40, 20);
+        let mut m = frame(w, h, |x, y| (4..16).contains(&x) && (4..16).contains(&y));
+        m[10 * w + 30] = 0.9;
+        let mut r = refiner(w, h);
+        r.set_guarantee_click(true);
+        assert_eq!(r.refine_mask(&m, 30.5 / 40.0, 0.5)[10 * w + 30], 0.9);
+        r.refine_mask(&m, 0.25, 0.5);
+        r.lock_selection();
+        let out = r.refine_mask(&m, 30.5 / 40.0, 0.5);
+        assert!(out[10 * w + 10] > 0.5);
+        assert_eq!(out[10 * w + 30], 0.0);
+    }
+
+    #[test]
+    fn guarantee_click_only_touches_the_first_click() {
+        let (w, h) = (40, 20);
+        let mut m = frame(w, h, |x, y| (4..16).contains(&x) && (4..16).contains(&y));
+        m[10 * w + 30] = 0.9;
+        m[5 * w + 34] = 0.8;
+        let mut r = refiner(w, h);
+        r.set_guarantee_click(true);
+        let out = r.refine_multi(&m, &[30.5 / 40.0, 0.525, 34.5 / 40.0, 0.275]);
+        assert_eq!(out[10 * w + 30], 0.9);
+        assert_eq!(out[5 * w + 34], 0.0);
+    }
+
+    #[test]
+    fn distance_transform_matches_brute_force() {
+        let (w, h) = (13, 9);
+        let mask: Vec<u8> = (0..w * h).map(|i| ((i * 37 + i / 5) % 11 > 2) as u8).collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        for edge_background in [false, true] {
+            let p = edge_background as i32;
+            let background = |x: i32, y: i32| {
+                if x < 0 || y < 0 || x >= w as i32 || y >= h as i32 {
+                    return true;
+                }
+                mask[y as usize * w + x as usize] == 0
+            };
+            let dt = r.distance_transform(&mask, edge_background);
+            for (i, &d) in dt.iter().enumerate() {
+                let (x, y) = ((i % w) as i32, (i / w) as i32);
+                let mut best = f32::INFINITY;
+                for by in -p..h as i32 + p {
+                    for bx in -p..w as i32 + p {
+                        if background(bx, by) {
+                            best = best.min((((bx - x).pow(2) + (by - y).pow(2)) as f32).sqrt());
                         }
                     }
-                    if found { break; }
                 }
-                if found { break; }
+                assert!((d - best).abs() < 1e-4, "({x}, {y}) edge {edge_background}: {d} vs {best}");
             }
-            if !found { return; }
         }
+    }
 
-        while let Some((x, y)) = q.pop_front() {
-            let idx = y * w + x;
-            if out[idx] == 0 && img[idx] == 1 {
-                out[idx] = 1;
-                if x > 0 { q.push_back((x - 1, y)); }
-                if x < w - 1 { q.push_back((x + 1, y)); }
-                if y > 0 { q.push_back((x, y - 1)); }
-                if y < h - 1 { q.push_back((x, y + 1)); }
+    #[test]
+    fn shrinking_the_history_keeps_the_newest_frames() {
+        let (w, h) = (8, 8);
+        let mut r = SubjectRefiner::new(w, h, 8);
+        r.set_keep_averaged(true);
+        for k in 1..=8 {
+            r.refine_mask(&vec![k as f32 / 10.0; w * h], 0.5, 0.5);
+        }
+        r.set_max_history(3);
+        let kept: Vec<f32> = r.history.iter().map(|f| f.decode()[0]).collect();
+        assert_eq!(kept, [0.6, 0.7, 0.8]);
+        r.refine_mask(&vec![0.9; w * h], 0.5, 0.5);
+        assert!(r.last_averaged.iter().all(|&v| (v - 0.8).abs() < 1e-6));
+    }
+
+    #[test]
+    fn auto_roi_matches_the_full_frame_result() {
+        let (w, h) = (50, 40);
+        let m = frame(w, h, |x, y| (15..30).contains(&x) && (10..25).contains(&y) || (40..48).contains(&x) && (30..38).contains(&y));
+        let mut roi = refiner(w, h);
+        roi.set_auto_roi(true, 4);
+        let mut full = refiner(w, h);
+        for _ in 0..2 {
+            assert_eq!(roi.refine_mask(&m, 0.4, 0.4), full.refine_mask(&m, 0.4, 0.4));
+        }
+        assert!(roi.roi.is_some());
+        assert_eq!((roi.width, roi.height), (w, h));
+    }
+
+    #[test]
+    fn gamma_lut_is_reused_until_the_feather_space_changes() {
+        let (w, h) = (20, 20);
+        let m: Vec<f32> = (0..w * h).map(|i| (i % w) as f32 / w as f32).collect();
+        let mut r = refiner(w, h);
+        r.set_feather_space(false);
+        for _ in 0..2 {
+            let out = r.refine_mask(&m, 0.9, 0.5);
+            r.antialias_edge(&out, 2);
+        }
+        assert_eq!(r.feather_lut_builds(), 1);
+        r.set_feather_space(true);
+        r.set_feather_space(false);
+        r.antialias_edge(&m, 2);
+        assert_eq!(r.feather_lut_builds(), 2);
+    }
+
+    #[test]
+    fn edge_padding_keeps_a_full_frame_mask_through_erosion() {
+        let (w, h) = (30, 20);
+        let m = vec![1.0; w * h];
+        let mut r = SubjectRefiner::new(w, h, 1);
+        r.set_erode_radius(3);
+        r.set_dilate_radius(3);
+        let out = r.refine_mask(&m, 0.5, 0.5);
+        assert_eq!(out[0], 0.0);
+        let mut padded = SubjectRefiner::new(w, h, 1);
+        padded.set_erode_radius(3);
+        padded.set_dilate_radius(3);
+        padded.set_edge_padding(3);
+        let out = padded.refine_mask(&m, 0.5, 0.5);
+        assert!(out.iter().all(|&v| v == 1.0));
+        assert_eq!((padded.width, padded.height), (w, h));
+    }
+
+    #[test]
+    fn byte_history_stays_within_half_a_step_of_full_precision() {
+        let (w, h) = (16, 16);
+        let mut full = SubjectRefiner::new(w, h, 5);
+        let mut bytes = SubjectRefiner::new(w, h, 5);
+        bytes.set_history_precision(8);
+        for r in [&mut full, &mut bytes] {
+            r.set_keep_averaged(true);
+        }
+        for k in 0..7 {
+            let m: Vec<f32> = (0..w * h).map(|i| ((i * 31 + k * 17) % 97) as f32 / 96.0).collect();
+            full.refine_mask(&m, 0.5, 0.5);
+            bytes.refine_mask(&m, 0.5, 0.5);
+            for (a, b) in full.last_averaged.iter().zip(&bytes.last_averaged) {
+                assert!((a - b).abs() <= 1.0 / 510.0 + 1e-6);
             }
         }
+        assert!(bytes.history.iter().all(|f| matches!(f, Frame::Byte(_))));
+    }
+
+    #[test]
+    fn stick_figure_has_five_end_points() {
+        let (w, h) = (41, 41);
+        let figure: Vec<u8> = frame(w, h, |x, y| {
+            let torso = (19..22).contains(&x) && (4..27).contains(&y);
+            let arms = (8..33).contains(&x) && (11..14).contains(&y);
+            let hips = (12..29).contains(&x) && (24..27).contains(&y);
+            let legs = ((12..15).contains(&x) || (26..29).contains(&x)) && (24..38).contains(&y);
+            torso || arms || hips || legs
+        })
+        .iter()
+        .map(|&v| v as u8)
+        .collect();
+        let r = SubjectRefiner::new(w, h, 1);
+        let points = r.skeleton_keypoints(&figure);
+        let ends = points.chunks_exact(3).filter(|p| p[2] == 1).count();
+        assert_eq!(ends, 5);
+        assert!(points.chunks_exact(3).any(|p| p[2] == 2));
+    }
+
+    #[test]
+    fn dominant_scale_reads_the_average_only_when_kept() {
+        let (w, h) = (32, 32);
+        let stripes = |phase: usize| frame(w, h, move |x, _| (x + phase) % 8 < 4);
+        let mut kept = SubjectRefiner::new(w, h, 2);
+        kept.set_keep_averaged(true);
+        let mut raw = SubjectRefiner::new(w, h, 2);
+        for r in [&mut kept, &mut raw] {
+            r.refine_all(&stripes(0));
+            r.refine_all(&stripes(4));
+        }
+        // The two phases average to a constant 0.5, the last input is stripes
+        assert_eq!(kept.dominant_scale(), 0.0);
+        assert!(raw.dominant_scale() > 0.0);
+    }
+
+    #[test]
+    fn chunked_refine_matches_refine_mask() {
+        let (w, h) = (48, 32);
+        let mut one = SubjectRefiner::new(w, h, 3);
+        let mut chunked = SubjectRefiner::new(w, h, 3);
+        for r in [&mut one, &mut chunked] {
+            r.set_threshold(0.4);
+            r.set_erode_radius(2);
+            r.set_dilate_radius(3);
+            r.set_element_shape(1, 0);
+            r.set_alpha_gamma(1.5);
+            r.set_history_precision(8);
+        }
+        for t in 0..4 {
+            let m: Vec<f32> = (0..w * h)
+                .map(|i| {
+                    let (x, y) = (i % w, i / w);
+                    let body = (6 + t..26 + t).contains(&x) && (4..28).contains(&y);
+                    let other = (34..44).contains(&x) && (8..20).contains(&y);
+                    let bridge = (26 + t..34).contains(&x) && y == 12;
+                    if body || other || bridge { 0.5 + (i % 7) as f32 / 14.0 } else { 0.0 }
+                })
+                .collect();
+            let expected = one.refine_mask(&m, 0.3, 0.5);
+            assert!(chunked.refine_mask_begin(&m, 0.3, 0.5));
+            while !chunked.refine_mask_step(5) {}
+            assert_eq!(chunked.refine_mask_finish(), expected);
+            assert_eq!(chunked.last_bbox, one.last_bbox);
+        }
+        chunked.set_auto_gain(true);
+        assert!(!chunked.refine_mask_begin(&vec![0.0; w * h], 0.5, 0.5));
     }
 }